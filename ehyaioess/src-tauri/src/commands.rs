@@ -1,31 +1,65 @@
 // Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
 
-use chatgpt::prelude::ChatGPT;
+use futures_util::{
+    future::{AbortRegistration, Abortable},
+    StreamExt,
+};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc, time::Instant};
 use tauri::{async_runtime::RwLock, Manager, State};
 
 use crate::{
-    models::{
-        Conversation, ConversationEvent, ConversationManager, ConversationMessageAddedEvent,
-        ConversationTitleChangedEvent, MyError,
+    context::{
+        estimate_text_tokens, estimate_tokens, SummaryRequest, DEFAULT_REPLY_TOKENS,
+        MAX_SUMMARY_TOKENS,
+    },
+    embeddings::{
+        self, SemanticSearchResult, StoredEmbedding, EMBEDDING_BATCH_SIZE, LOCAL_EMBEDDING_MODEL,
     },
     events::{
-        ConversationMessageAddedEventPayload,
-        ConversationTitleChangedEventPayload,
+        AssistantRetryingEventPayload, ConversationContextSummarizedEventPayload,
+        ConversationMessageAddedEventPayload, ConversationMessageAlternativeEventPayload,
+        ConversationMessageCancelledEventPayload, ConversationMessageDeltaEventPayload,
+        ConversationPurgedEventPayload, ConversationSettingsChangedEventPayload,
+        ConversationStatusChangedEventPayload, ConversationSystemPromptChangedEventPayload,
+        ConversationTitleChangedEventPayload, SpendingLimitWarningEventPayload,
+    },
+    models::{
+        parse_uuid, Conversation, ConversationContextSummarizedEvent, ConversationEvent,
+        ConversationHistoryPage, ConversationManager, ConversationMessage,
+        ConversationMessageAddedEvent, ConversationMessageAlternativeAddedEvent,
+        ConversationMessageAlternativeSelectedEvent, ConversationMessageCancelledEvent,
+        ConversationMessageEditedEvent, ConversationMessagesPage, ConversationSettings,
+        ConversationSettingsChangedEvent, ConversationStatus, ConversationStatusChangedEvent,
+        ConversationSystemPromptChangedEvent, ConversationTitleChangedEvent, GenerationRegistry,
+        MyError, ProviderError, SpendingLimit, MAX_TITLE_TOKENS,
     },
+    providers::{ChatCompletionChunk, ChatMessage, ChatProvider, RetryNotice},
+    search::{SearchFilters, SearchResult},
+    templates::{PromptTemplate, TemplateManager},
+    usage::{month_start, CompletionUsage, UsagePeriod, UsageRecord, UsageReport},
 };
 
 #[tauri::command]
 #[specta::specta]
 pub async fn list_conversation_titles(
     conversation_manager: State<'_, RwLock<ConversationManager>>,
+) -> Result<HashMap<String, String>, MyError> {
+    list_conversation_titles_with_status(conversation_manager, ConversationStatus::Active).await
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn list_conversation_titles_with_status(
+    conversation_manager: State<'_, RwLock<ConversationManager>>,
+    status: ConversationStatus,
 ) -> Result<HashMap<String, String>, MyError> {
     let mgr = conversation_manager.read().await;
     let titles_by_id = mgr
-        .conversations
-        .iter()
-        .map(|(id, conv)| (id.to_string(), conv.get_title().into_owned()))
+        .summaries()
+        .into_iter()
+        .filter(|summary| summary.status == status)
+        .map(|summary| (summary.id.to_string(), summary.title))
         .collect();
     Ok(titles_by_id)
 }
@@ -36,14 +70,10 @@ pub async fn get_conversation(
     conversation_manager: State<'_, RwLock<ConversationManager>>,
     conversation_id: &str,
 ) -> Result<Conversation, MyError> {
-    let conversation_id =
-        uuid::Uuid::parse_str(conversation_id).map_err(|_| MyError::FindByIDFail)?;
+    let conversation_id = parse_uuid(conversation_id)?;
     let mgr = conversation_manager.read().await;
-    let conversation = mgr
-        .conversations
-        .get(&conversation_id)
-        .ok_or(MyError::FindByIDFail)?;
-    Ok(conversation.clone())
+    let conversation = mgr.get(&conversation_id)?;
+    Ok(Conversation::clone(&conversation))
 }
 
 #[tauri::command]
@@ -52,13 +82,9 @@ pub async fn get_conversation_title(
     conversation_manager: State<'_, RwLock<ConversationManager>>,
     conversation_id: &str,
 ) -> Result<String, MyError> {
-    let conversation_id =
-        uuid::Uuid::parse_str(conversation_id).map_err(|_| MyError::FindByIDFail)?;
+    let conversation_id = parse_uuid(conversation_id)?;
     let mgr = conversation_manager.read().await;
-    let conversation = mgr
-        .conversations
-        .get(&conversation_id)
-        .ok_or(MyError::FindByIDFail)?;
+    let conversation = mgr.get(&conversation_id)?;
     Ok(conversation.get_title().into_owned())
 }
 
@@ -67,26 +93,162 @@ pub async fn get_conversation_title(
 pub async fn get_conversation_messages(
     conversation_manager: State<'_, RwLock<ConversationManager>>,
     conversation_id: &str,
-) -> Result<Vec<ConversationMessageAddedEvent>, MyError> {
-    let conversation_id =
-        uuid::Uuid::parse_str(conversation_id).map_err(|_| MyError::FindByIDFail)?;
+) -> Result<Vec<ConversationMessage>, MyError> {
+    let conversation_id = parse_uuid(conversation_id)?;
     let mgr = conversation_manager.read().await;
-    let conversation = mgr
-        .conversations
-        .get(&conversation_id)
-        .ok_or(MyError::FindByIDFail)?;
-    let message_events = conversation
-        .history
-        .iter()
-        .filter_map(|record| {
-            if let ConversationEvent::MessageAdded(msg) = &record.event {
-                Some(msg.clone())
-            } else {
-                None
-            }
+    let conversation = mgr.get(&conversation_id)?;
+    Ok(conversation.get_messages())
+}
+
+fn parse_cursor(cursor: Option<String>) -> Result<Option<uuid::Uuid>, MyError> {
+    cursor.map(|cursor| parse_uuid(&cursor)).transpose()
+}
+
+/// Up to `limit` records right after the `after` record, or right before the `before` one.
+///
+/// Without either cursor it returns the latest records.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_conversation_history_page(
+    conversation_manager: State<'_, RwLock<ConversationManager>>,
+    conversation_id: &str,
+    before: Option<String>,
+    after: Option<String>,
+    limit: u32,
+) -> Result<ConversationHistoryPage, MyError> {
+    let conversation_id = parse_uuid(conversation_id)?;
+    let (before, after) = (parse_cursor(before)?, parse_cursor(after)?);
+    let mgr = conversation_manager.read().await;
+    let conversation = mgr.get(&conversation_id)?;
+    conversation.get_history_page(before, after, limit as usize)
+}
+
+/// Same as `get_conversation_history_page` for the messages on the active branch, by message id.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_conversation_messages_page(
+    conversation_manager: State<'_, RwLock<ConversationManager>>,
+    conversation_id: &str,
+    before: Option<String>,
+    after: Option<String>,
+    limit: u32,
+) -> Result<ConversationMessagesPage, MyError> {
+    let conversation_id = parse_uuid(conversation_id)?;
+    let (before, after) = (parse_cursor(before)?, parse_cursor(after)?);
+    let mgr = conversation_manager.read().await;
+    let conversation = mgr.get(&conversation_id)?;
+    conversation.get_messages_page(before, after, limit as usize)
+}
+
+/// Finds the messages and titles containing every word of the query, newest first.
+#[tauri::command]
+#[specta::specta]
+pub async fn search_conversations(
+    conversation_manager: State<'_, RwLock<ConversationManager>>,
+    query: &str,
+    filters: SearchFilters,
+) -> Result<Vec<SearchResult>, MyError> {
+    let mgr = conversation_manager.read().await;
+    mgr.search(query, &filters)
+        .map_err(|e| MyError::SearchFail {
+            message: e.to_string(),
+        })
+}
+
+/// Embeds the texts, checking the spending limits first and saving what the request cost when
+/// it goes to the provider.
+async fn embed(
+    app_handle: &tauri::AppHandle,
+    model: Option<&str>,
+    texts: &[String],
+) -> Result<Vec<Vec<f32>>, MyError> {
+    let chat_provider = app_handle.state::<Arc<dyn ChatProvider>>();
+    let conversation_manager = app_handle.state::<RwLock<ConversationManager>>();
+
+    let Some(model) = model else {
+        return Ok(embeddings::embed(chat_provider.as_ref(), None, texts)
+            .await?
+            .vectors);
+    };
+    check_spending_limits(
+        app_handle,
+        None,
+        &[PlannedRequest {
+            model,
+            prompt_tokens: texts.iter().map(|text| estimate_text_tokens(text)).sum(),
+            max_completion_tokens: 0,
+        }],
+    )
+    .await?;
+    let started = Instant::now();
+    let embedded = embeddings::embed(chat_provider.as_ref(), Some(model), texts).await?;
+    let usage =
+        CompletionUsage::for_embeddings(model, embedded.prompt_tokens, texts, started.elapsed());
+    conversation_manager
+        .read()
+        .await
+        .record_usage(&[UsageRecord::standalone(usage)])
+        .map_err(|e| MyError::ConversationWriteToDiskFail {
+            conversation_id: None,
+            message: e.to_string(),
+        })?;
+    Ok(embedded.vectors)
+}
+
+/// Finds the conversations closest in meaning to the query, embedding the messages that aren't yet.
+#[tauri::command]
+#[specta::specta]
+pub async fn semantic_search(
+    app_handle: tauri::AppHandle,
+    config: State<'_, crate::config::Config>,
+    conversation_manager: State<'_, RwLock<ConversationManager>>,
+    query: &str,
+    top_k: u32,
+) -> Result<Vec<SemanticSearchResult>, MyError> {
+    let model = config.embedding_model.as_deref();
+    let model_name = model.unwrap_or(LOCAL_EMBEDDING_MODEL);
+
+    // The lock isn't held while the provider computes the vectors.
+    let pending = conversation_manager
+        .read()
+        .await
+        .pending_embeddings(model_name)
+        .map_err(|e| MyError::SearchFail {
+            message: e.to_string(),
+        })?;
+    // Each batch is saved as it comes back, so a failure only loses the batch that failed.
+    for batch in pending.chunks(EMBEDDING_BATCH_SIZE) {
+        let texts: Vec<String> = batch.iter().map(|(_, _, text)| text.clone()).collect();
+        let vectors = embed(&app_handle, model, &texts).await?;
+        let embeddings = batch
+            .iter()
+            .zip(vectors)
+            .map(|((event_id, conversation_id, _), vector)| StoredEmbedding {
+                event_id: *event_id,
+                conversation_id: *conversation_id,
+                model: model_name.to_string(),
+                vector,
+            })
+            .collect();
+        conversation_manager
+            .read()
+            .await
+            .add_embeddings(embeddings)
+            .map_err(|e| MyError::ConversationWriteToDiskFail {
+                conversation_id: None,
+                message: e.to_string(),
+            })?;
+    }
+
+    let query_vector = embed(&app_handle, model, &[query.to_string()])
+        .await?
+        .pop()
+        .ok_or_else(|| MyError::EmbeddingFail(ProviderError::new("no embedding was returned")))?;
+    let mgr = conversation_manager.read().await;
+    mgr.semantic_search(&query_vector, model_name, top_k as usize)
+        .map_err(|e| MyError::SearchFail {
+            message: e.to_string(),
         })
-        .collect();
-    Ok(message_events)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -98,15 +260,17 @@ pub struct ConversationAddedEvent {
 #[specta::specta]
 pub async fn new_conversation(
     conversation_manager: State<'_, RwLock<ConversationManager>>,
-    config: State<'_, crate::config::Config>,
     app_handle: tauri::AppHandle,
 ) -> Result<Conversation, MyError> {
     let mut mgr = conversation_manager.write().await;
     let conv = Conversation::new();
 
-    mgr.conversations.insert(conv.id, conv.clone());
-    mgr.write_to_disk(&config.conversation_history_save_path)
-        .map_err(|_| MyError::ConversationWriteToDiskFail)?;
+    mgr.insert(conv.clone());
+    mgr.write_to_disk()
+        .map_err(|e| MyError::ConversationWriteToDiskFail {
+            conversation_id: Some(conv.id),
+            message: e.to_string(),
+        })?;
 
     // Drop the lock before emitting events.
     drop(mgr);
@@ -119,7 +283,10 @@ pub async fn new_conversation(
                 title: conv.get_title().into_owned(),
             },
         )
-        .map_err(|_| MyError::EmitFail)?;
+        .map_err(|e| MyError::EmitFail {
+            event: "new_conversation".to_string(),
+            message: e.to_string(),
+        })?;
     Ok(conv)
 }
 
@@ -127,35 +294,35 @@ pub async fn new_conversation(
 #[specta::specta]
 pub async fn set_conversation_title(
     conversation_manager: State<'_, RwLock<ConversationManager>>,
-    config: State<'_, crate::config::Config>,
     app_handle: tauri::AppHandle,
     conversation_id: &str,
     new_title: &str,
 ) -> Result<(), MyError> {
-    let conversation_id =
-        uuid::Uuid::parse_str(conversation_id).map_err(|_| MyError::UUIDParseFail)?;
+    let conversation_id = parse_uuid(conversation_id)?;
     let new_title_trimmed = new_title.trim();
 
     {
         let mut mgr = conversation_manager.write().await;
-        let conv = mgr
-            .conversations
-            .get_mut(&conversation_id)
-            .ok_or(MyError::FindByIDFail)?;
+        let conv = mgr.get_mut(&conversation_id)?;
         let current_title = conv.get_title();
         if current_title.as_ref() == new_title_trimmed {
             return Ok(());
         }
         conv.add_event(ConversationTitleChangedEvent {
             new_title: new_title_trimmed.to_string(),
+            auto_generated: false,
+            usage: None,
         })
     };
 
     conversation_manager
         .read()
         .await
-        .write_to_disk(&config.conversation_history_save_path)
-        .map_err(|_| MyError::ConversationWriteToDiskFail)?;
+        .write_to_disk()
+        .map_err(|e| MyError::ConversationWriteToDiskFail {
+            conversation_id: Some(conversation_id),
+            message: e.to_string(),
+        })?;
 
     app_handle
         .emit_all(
@@ -163,55 +330,776 @@ pub async fn set_conversation_title(
             ConversationTitleChangedEventPayload {
                 conversation_id,
                 new_title: new_title_trimmed.to_string(),
+                auto_generated: false,
             },
         )
-        .map_err(|_| MyError::EmitFail)?;
+        .map_err(|e| MyError::EmitFail {
+            event: "conversation_title_changed".to_string(),
+            message: e.to_string(),
+        })?;
+
+    Ok(())
+}
+
+async fn set_conversation_status(
+    app_handle: &tauri::AppHandle,
+    conversation_manager: &RwLock<ConversationManager>,
+    conversation_id: &str,
+    status: ConversationStatus,
+) -> Result<(), MyError> {
+    let conversation_id = parse_uuid(conversation_id)?;
+
+    let title = {
+        let mut mgr = conversation_manager.write().await;
+        let conv = mgr.get_mut(&conversation_id)?;
+        if conv.get_status() == status {
+            return Ok(());
+        }
+        conv.add_event(ConversationStatusChangedEvent { status });
+        conv.get_title().into_owned()
+    };
+
+    conversation_manager
+        .read()
+        .await
+        .write_to_disk()
+        .map_err(|e| MyError::ConversationWriteToDiskFail {
+            conversation_id: Some(conversation_id),
+            message: e.to_string(),
+        })?;
+
+    app_handle
+        .emit_all(
+            "conversation_status_changed",
+            ConversationStatusChangedEventPayload {
+                conversation_id,
+                status,
+                title,
+            },
+        )
+        .map_err(|e| MyError::EmitFail {
+            event: "conversation_status_changed".to_string(),
+            message: e.to_string(),
+        })?;
 
     Ok(())
 }
 
 #[tauri::command]
 #[specta::specta]
-pub async fn new_conversation_user_message(
+pub async fn archive_conversation(
+    app_handle: tauri::AppHandle,
+    conversation_manager: State<'_, RwLock<ConversationManager>>,
+    conversation_id: &str,
+) -> Result<(), MyError> {
+    set_conversation_status(
+        &app_handle,
+        &conversation_manager,
+        conversation_id,
+        ConversationStatus::Archived,
+    )
+    .await
+}
+
+/// Moves the conversation to the trash, it can be restored until `purge_trash` removes it.
+#[tauri::command]
+#[specta::specta]
+pub async fn delete_conversation(
+    app_handle: tauri::AppHandle,
+    conversation_manager: State<'_, RwLock<ConversationManager>>,
+    conversation_id: &str,
+) -> Result<(), MyError> {
+    set_conversation_status(
+        &app_handle,
+        &conversation_manager,
+        conversation_id,
+        ConversationStatus::Trashed,
+    )
+    .await
+}
+
+/// Brings an archived or trashed conversation back to the conversation list.
+#[tauri::command]
+#[specta::specta]
+pub async fn restore_conversation(
+    app_handle: tauri::AppHandle,
+    conversation_manager: State<'_, RwLock<ConversationManager>>,
+    conversation_id: &str,
+) -> Result<(), MyError> {
+    set_conversation_status(
+        &app_handle,
+        &conversation_manager,
+        conversation_id,
+        ConversationStatus::Active,
+    )
+    .await
+}
+
+/// Removes the conversations that have been in the trash longer than the retention period.
+#[tauri::command]
+#[specta::specta]
+pub async fn purge_trash(
     app_handle: tauri::AppHandle,
     config: State<'_, crate::config::Config>,
     conversation_manager: State<'_, RwLock<ConversationManager>>,
+) -> Result<Vec<uuid::Uuid>, MyError> {
+    let retention_ms = i64::from(config.trash_retention_days) * 24 * 60 * 60 * 1000;
+    let purged = {
+        let mut mgr = conversation_manager.write().await;
+        let purged = mgr.purge_trash(chrono::Utc::now().timestamp_millis(), retention_ms)?;
+        if purged.is_empty() {
+            return Ok(purged);
+        }
+        mgr.write_to_disk()
+            .map_err(|e| MyError::ConversationWriteToDiskFail {
+                conversation_id: None,
+                message: e.to_string(),
+            })?;
+        purged
+    };
+
+    for conversation_id in &purged {
+        app_handle
+            .emit_all(
+                "conversation_purged",
+                ConversationPurgedEventPayload {
+                    conversation_id: *conversation_id,
+                },
+            )
+            .map_err(|e| MyError::EmitFail {
+                event: "conversation_purged".to_string(),
+                message: e.to_string(),
+            })?;
+    }
+
+    Ok(purged)
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn get_conversation_settings(
+    conversation_manager: State<'_, RwLock<ConversationManager>>,
+    conversation_id: &str,
+) -> Result<ConversationSettings, MyError> {
+    let conversation_id = parse_uuid(conversation_id)?;
+    let mgr = conversation_manager.read().await;
+    let conversation = mgr.get(&conversation_id)?;
+    Ok(conversation.get_settings().into_owned())
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn set_conversation_settings(
+    conversation_manager: State<'_, RwLock<ConversationManager>>,
+    app_handle: tauri::AppHandle,
+    conversation_id: &str,
+    settings: ConversationSettings,
+) -> Result<(), MyError> {
+    let conversation_id = parse_uuid(conversation_id)?;
+    settings.validate()?;
+
+    {
+        let mut mgr = conversation_manager.write().await;
+        let conv = mgr.get_mut(&conversation_id)?;
+        if conv.get_settings().as_ref() == &settings {
+            return Ok(());
+        }
+        conv.add_event(ConversationSettingsChangedEvent {
+            settings: settings.clone(),
+        })
+    };
+
+    conversation_manager
+        .read()
+        .await
+        .write_to_disk()
+        .map_err(|e| MyError::ConversationWriteToDiskFail {
+            conversation_id: Some(conversation_id),
+            message: e.to_string(),
+        })?;
+
+    app_handle
+        .emit_all(
+            "conversation_settings_changed",
+            ConversationSettingsChangedEventPayload {
+                conversation_id,
+                settings,
+            },
+        )
+        .map_err(|e| MyError::EmitFail {
+            event: "conversation_settings_changed".to_string(),
+            message: e.to_string(),
+        })?;
+
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn get_system_prompt(
+    conversation_manager: State<'_, RwLock<ConversationManager>>,
+    conversation_id: &str,
+) -> Result<Option<String>, MyError> {
+    let conversation_id = parse_uuid(conversation_id)?;
+    let mgr = conversation_manager.read().await;
+    let conversation = mgr.get(&conversation_id)?;
+    Ok(conversation.get_system_prompt().cloned())
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn set_system_prompt(
+    conversation_manager: State<'_, RwLock<ConversationManager>>,
+    app_handle: tauri::AppHandle,
     conversation_id: &str,
     content: &str,
 ) -> Result<(), MyError> {
-    let conversation_id =
-        uuid::Uuid::parse_str(conversation_id).map_err(|_| MyError::UUIDParseFail)?;
+    let conversation_id = parse_uuid(conversation_id)?;
+    let content_trimmed = content.trim();
 
     {
         let mut mgr = conversation_manager.write().await;
-        let conv = mgr
-            .conversations
-            .get_mut(&conversation_id)
-            .ok_or(MyError::UUIDParseFail)?;
+        let conv = mgr.get_mut(&conversation_id)?;
+        let current_prompt = conv
+            .get_system_prompt()
+            .map_or("", |prompt| prompt.as_str());
+        if current_prompt == content_trimmed {
+            return Ok(());
+        }
+        conv.add_event(ConversationSystemPromptChangedEvent {
+            content: content_trimmed.to_string(),
+        })
+    };
+
+    conversation_manager
+        .read()
+        .await
+        .write_to_disk()
+        .map_err(|e| MyError::ConversationWriteToDiskFail {
+            conversation_id: Some(conversation_id),
+            message: e.to_string(),
+        })?;
+
+    app_handle
+        .emit_all(
+            "conversation_system_prompt_changed",
+            ConversationSystemPromptChangedEventPayload {
+                conversation_id,
+                content: content_trimmed.to_string(),
+            },
+        )
+        .map_err(|e| MyError::EmitFail {
+            event: "conversation_system_prompt_changed".to_string(),
+            message: e.to_string(),
+        })?;
+
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn new_conversation_user_message(
+    app_handle: tauri::AppHandle,
+    conversation_manager: State<'_, RwLock<ConversationManager>>,
+    conversation_id: &str,
+    content: &str,
+) -> Result<(), MyError> {
+    let conversation_id = parse_uuid(conversation_id)?;
+
+    let message_id = {
+        let mut mgr = conversation_manager.write().await;
+        let conv = mgr.get_mut(&conversation_id)?;
         conv.add_event(ConversationMessageAddedEvent {
             author: crate::models::ChatRole::User,
             content: content.to_string(),
+            usage: None,
         })
-        .clone()
+        .id
     };
 
     conversation_manager
         .read()
         .await
-        .write_to_disk(&config.conversation_history_save_path)
-        .map_err(|_| MyError::ConversationWriteToDiskFail)?;
+        .write_to_disk()
+        .map_err(|e| MyError::ConversationWriteToDiskFail {
+            conversation_id: Some(conversation_id),
+            message: e.to_string(),
+        })?;
 
     app_handle
         .emit_all(
             "conversation_message_added",
             ConversationMessageAddedEventPayload {
                 conversation_id,
+                message_id,
                 author: crate::models::ChatRole::User,
                 content: content.to_string(),
             },
         )
-        .map_err(|_| MyError::EmitFail)?;
+        .map_err(|e| MyError::EmitFail {
+            event: "conversation_message_added".to_string(),
+            message: e.to_string(),
+        })?;
+
+    Ok(())
+}
+
+/// How a streamed assistant reply ended, the content is partial when cancelled.
+enum AssistantReply {
+    Completed(String, CompletionUsage),
+    Cancelled(String, CompletionUsage),
+}
+
+/// Streams a reply to `messages`, forwarding each chunk as a `conversation_message_delta` event.
+async fn stream_assistant_reply(
+    app_handle: &tauri::AppHandle,
+    chat_provider: &dyn ChatProvider,
+    abort_registration: AbortRegistration,
+    conversation_id: uuid::Uuid,
+    message_id: uuid::Uuid,
+    messages: &[ChatMessage],
+    settings: &ConversationSettings,
+) -> Result<AssistantReply, MyError> {
+    let started = Instant::now();
+    let mut response = String::new();
+    let mut reported_usage = None;
+    let generation = Abortable::new(
+        async {
+            let on_retry = |notice: &RetryNotice| {
+                let payload = AssistantRetryingEventPayload {
+                    conversation_id,
+                    message_id,
+                    attempt: notice.attempt,
+                    max_attempts: notice.max_attempts,
+                    delay_ms: notice.delay.as_millis() as u32,
+                    reason: notice.reason.clone(),
+                };
+                if app_handle.emit_all("assistant_retrying", payload).is_err() {
+                    eprintln!("Failed to emit assistant_retrying");
+                }
+            };
+            let mut stream = chat_provider
+                .complete_streaming(messages, settings, &on_retry)
+                .await?;
+
+            let mut sequence = 0;
+            while let Some(chunk) = stream.next().await {
+                let delta = match chunk? {
+                    ChatCompletionChunk::Delta(delta) => delta,
+                    ChatCompletionChunk::Usage(usage) => {
+                        reported_usage = Some(usage);
+                        continue;
+                    }
+                };
+                response.push_str(&delta);
+                app_handle
+                    .emit_all(
+                        "conversation_message_delta",
+                        ConversationMessageDeltaEventPayload {
+                            conversation_id,
+                            message_id,
+                            chunk: delta,
+                            sequence,
+                        },
+                    )
+                    .map_err(|e| MyError::EmitFail {
+                        event: "conversation_message_delta".to_string(),
+                        message: e.to_string(),
+                    })?;
+                sequence += 1;
+            }
+            Ok::<(), MyError>(())
+        },
+        abort_registration,
+    );
+
+    let finished = generation.await;
+    let config = app_handle.state::<crate::config::Config>();
+    let usage = CompletionUsage::new(
+        config.chat_model(settings.model.as_deref()),
+        reported_usage,
+        messages,
+        &response,
+        started.elapsed(),
+    );
+    if let Ok(result) = finished {
+        result?;
+        // The stream is empty when the server responds with an error.
+        if response.is_empty() {
+            return Err(MyError::ConversationAIResponseFail(ProviderError::new(
+                "the reply is empty",
+            )));
+        }
+        Ok(AssistantReply::Completed(response, usage))
+    } else {
+        // Aborting drops the in-flight request, keep whatever was received so far.
+        Ok(AssistantReply::Cancelled(response, usage))
+    }
+}
+
+/// Fits the active branch into the model's context window, summarizing the turns left out first.
+///
+/// Also returns the settings to reply with, `skip_latest` leaves out the reply about to be replaced.
+async fn build_chat_context(
+    app_handle: &tauri::AppHandle,
+    conversation_id: uuid::Uuid,
+    skip_latest: bool,
+) -> Result<(Vec<ChatMessage>, ConversationSettings), MyError> {
+    let config = app_handle.state::<crate::config::Config>();
+    let conversation_manager = app_handle.state::<RwLock<ConversationManager>>();
+
+    let fit = |mgr: &ConversationManager| {
+        let conv = mgr.get(&conversation_id)?;
+        let mut settings = conv.get_settings().into_owned();
+        if let Some(cap) = config.max_tokens_per_request {
+            settings.max_tokens = Some(settings.max_tokens.map_or(cap, |max| max.min(cap)));
+        }
+        let budget = config
+            .context_window(settings.model.as_deref())
+            .saturating_sub(settings.max_tokens.unwrap_or(DEFAULT_REPLY_TOKENS));
+        let mut branch = conv.get_messages();
+        if skip_latest {
+            branch.pop();
+        }
+        Ok::<_, MyError>((conv.build_context(&branch, budget)?, settings))
+    };
+    let (context, settings) = fit(&*conversation_manager.read().await)?;
+    let Some(request) = context.summary_request else {
+        return Ok((context.messages, settings));
+    };
+    // The summary is paid for too, so it has to fit in the limits along with the reply.
+    check_spending_limits(
+        app_handle,
+        Some(conversation_id),
+        &[
+            PlannedRequest::chat(&config, &request.messages, &summary_settings(&settings)),
+            PlannedRequest::chat(&config, &context.messages, &settings),
+        ],
+    )
+    .await?;
+    // Without a summary the left out turns are only dropped, which still beats failing the reply.
+    if let Err(e) = summarize_context(app_handle, conversation_id, request, &settings).await {
+        eprintln!(
+            "Failed to summarize conversation {}: {}",
+            conversation_id, e
+        );
+        return Ok((context.messages, settings));
+    }
+    let (context, settings) = fit(&*conversation_manager.read().await)?;
+    Ok((context.messages, settings))
+}
+
+/// A request about to be sent, sized for the most it can cost.
+struct PlannedRequest<'a> {
+    model: &'a str,
+    prompt_tokens: u32,
+    /// The longest reply the request allows, embeddings have none.
+    max_completion_tokens: u32,
+}
+impl<'a> PlannedRequest<'a> {
+    fn chat(
+        config: &'a crate::config::Config,
+        messages: &[ChatMessage],
+        settings: &'a ConversationSettings,
+    ) -> Self {
+        Self {
+            model: config.chat_model(settings.model.as_deref()),
+            prompt_tokens: messages.iter().map(estimate_tokens).sum(),
+            max_completion_tokens: settings.max_tokens.unwrap_or(DEFAULT_REPLY_TOKENS),
+        }
+    }
+}
 
+/// Refuses requests that could take spending past a limit, and warns when they get close to one.
+///
+/// Requests made outside of a conversation only count towards the monthly limit. While a limit
+/// applies, requests to models missing from the price table are refused, as their cost is unknown.
+async fn check_spending_limits(
+    app_handle: &tauri::AppHandle,
+    conversation_id: Option<uuid::Uuid>,
+    requests: &[PlannedRequest<'_>],
+) -> Result<(), MyError> {
+    let config = app_handle.state::<crate::config::Config>();
+    let conversation_manager = app_handle.state::<RwLock<ConversationManager>>();
+
+    let now = chrono::Utc::now().timestamp_millis();
+    let mut limits = Vec::new();
+    if let Some(cap) = config.monthly_spending_limit {
+        limits.push((SpendingLimit::Monthly, cap, None, Some(month_start(now))));
+    }
+    if let (Some(cap), Some(conversation_id)) =
+        (config.conversation_spending_limit, conversation_id)
+    {
+        limits.push((
+            SpendingLimit::Conversation,
+            cap,
+            Some(conversation_id),
+            None,
+        ));
+    }
+    if limits.is_empty() {
+        return Ok(());
+    }
+    let mut cost = 0.0;
+    for request in requests {
+        let price =
+            config
+                .model_prices
+                .get(request.model)
+                .ok_or_else(|| MyError::UnpricedModelFail {
+                    model: request.model.to_string(),
+                })?;
+        cost += price.cost(request.prompt_tokens, request.max_completion_tokens);
+    }
+
+    for (limit, cap, conversation, since) in limits {
+        let spent = conversation_manager.read().await.spent(
+            &config.model_prices,
+            conversation.as_ref(),
+            since,
+        )?;
+        if spent + cost > cap {
+            return Err(MyError::SpendingLimitExceededFail { limit, spent, cap });
+        }
+        if spent + cost >= cap * config.spending_warning_threshold {
+            app_handle
+                .emit_all(
+                    "spending_limit_warning",
+                    SpendingLimitWarningEventPayload {
+                        conversation_id,
+                        limit,
+                        spent: spent + cost,
+                        cap,
+                    },
+                )
+                .map_err(|e| MyError::EmitFail {
+                    event: "spending_limit_warning".to_string(),
+                    message: e.to_string(),
+                })?;
+        }
+    }
+    Ok(())
+}
+
+/// The settings summaries are generated with, the model replying writes them.
+fn summary_settings(settings: &ConversationSettings) -> ConversationSettings {
+    ConversationSettings {
+        model: settings.model.clone(),
+        max_tokens: Some(MAX_SUMMARY_TOKENS),
+        ..Default::default()
+    }
+}
+
+/// Records a summary of the turns left out of the context window.
+async fn summarize_context(
+    app_handle: &tauri::AppHandle,
+    conversation_id: uuid::Uuid,
+    request: SummaryRequest,
+    settings: &ConversationSettings,
+) -> Result<(), MyError> {
+    let config = app_handle.state::<crate::config::Config>();
+    let chat_provider = app_handle.state::<Arc<dyn ChatProvider>>();
+    let conversation_manager = app_handle.state::<RwLock<ConversationManager>>();
+
+    let summary_settings = summary_settings(settings);
+    let started = Instant::now();
+    let completion = chat_provider
+        .complete(&request.messages, &summary_settings, &|_| {})
+        .await?;
+    let summary = completion.content.trim().to_string();
+    if summary.is_empty() {
+        return Err(MyError::ConversationAIResponseFail(ProviderError::new(
+            "the summary is empty",
+        )));
+    }
+    let usage = CompletionUsage::new(
+        config.chat_model(settings.model.as_deref()),
+        completion.usage,
+        &request.messages,
+        &summary,
+        started.elapsed(),
+    );
+
+    conversation_manager.write().await.add_event_with_id(
+        &conversation_id,
+        uuid::Uuid::new_v4(),
+        ConversationContextSummarizedEvent {
+            summary: summary.clone(),
+            through_alternative_id: request.through_alternative_id,
+            usage: Some(usage),
+        },
+    )?;
+
+    conversation_manager
+        .read()
+        .await
+        .write_to_disk()
+        .map_err(|e| MyError::ConversationWriteToDiskFail {
+            conversation_id: Some(conversation_id),
+            message: e.to_string(),
+        })?;
+
+    app_handle
+        .emit_all(
+            "conversation_context_summarized",
+            ConversationContextSummarizedEventPayload {
+                conversation_id,
+                summary,
+                through_alternative_id: request.through_alternative_id,
+            },
+        )
+        .map_err(|e| MyError::EmitFail {
+            event: "conversation_context_summarized".to_string(),
+            message: e.to_string(),
+        })?;
+    Ok(())
+}
+
+/// Titles the conversation from its first exchange, unless it already has a title.
+async fn generate_conversation_title(
+    app_handle: &tauri::AppHandle,
+    conversation_id: uuid::Uuid,
+) -> Result<(), MyError> {
+    let config = app_handle.state::<crate::config::Config>();
+    let chat_provider = app_handle.state::<Arc<dyn ChatProvider>>();
+    let conversation_manager = app_handle.state::<RwLock<ConversationManager>>();
+
+    let Some(messages) = conversation_manager
+        .read()
+        .await
+        .get(&conversation_id)?
+        .title_request()
+    else {
+        return Ok(());
+    };
+    let settings = ConversationSettings {
+        model: config.title_model.clone(),
+        max_tokens: Some(MAX_TITLE_TOKENS),
+        ..Default::default()
+    };
+    check_spending_limits(
+        app_handle,
+        Some(conversation_id),
+        &[PlannedRequest::chat(&config, &messages, &settings)],
+    )
+    .await?;
+    let started = Instant::now();
+    let completion = chat_provider
+        .complete(&messages, &settings, &|_| {})
+        .await?;
+    let usage = CompletionUsage::new(
+        config.chat_model(settings.model.as_deref()),
+        completion.usage,
+        &messages,
+        &completion.content,
+        started.elapsed(),
+    );
+    let title = crate::models::parse_generated_title(&completion.content).ok_or_else(|| {
+        MyError::ConversationAIResponseFail(ProviderError::new("the reply holds no title"))
+    })?;
+
+    {
+        let mut mgr = conversation_manager.write().await;
+        let conv = mgr.get_mut(&conversation_id)?;
+        // Someone may have set a title while this one was being generated, it was paid for anyway.
+        if conv
+            .get_latest_event::<ConversationTitleChangedEvent>()
+            .is_some()
+        {
+            return mgr
+                .record_usage(&[UsageRecord {
+                    conversation_id: Some(conversation_id),
+                    ..UsageRecord::standalone(usage)
+                }])
+                .map_err(|e| MyError::ConversationWriteToDiskFail {
+                    conversation_id: Some(conversation_id),
+                    message: e.to_string(),
+                });
+        }
+        conv.add_event(ConversationTitleChangedEvent {
+            new_title: title.clone(),
+            auto_generated: true,
+            usage: Some(usage),
+        });
+    }
+
+    conversation_manager
+        .read()
+        .await
+        .write_to_disk()
+        .map_err(|e| MyError::ConversationWriteToDiskFail {
+            conversation_id: Some(conversation_id),
+            message: e.to_string(),
+        })?;
+
+    app_handle
+        .emit_all(
+            "conversation_title_changed",
+            ConversationTitleChangedEventPayload {
+                conversation_id,
+                new_title: title,
+                auto_generated: true,
+            },
+        )
+        .map_err(|e| MyError::EmitFail {
+            event: "conversation_title_changed".to_string(),
+            message: e.to_string(),
+        })?;
+    Ok(())
+}
+
+/// Saves what was received of a cancelled reply, as a variant of `regenerated` when it replaced one.
+async fn record_cancelled_reply(
+    app_handle: &tauri::AppHandle,
+    conversation_manager: &RwLock<ConversationManager>,
+    conversation_id: uuid::Uuid,
+    message_id: uuid::Uuid,
+    regenerated: Option<uuid::Uuid>,
+    partial_content: String,
+    usage: CompletionUsage,
+) -> Result<(), MyError> {
+    let event: ConversationEvent = match regenerated {
+        Some(regenerated) => ConversationMessageAlternativeAddedEvent {
+            message_id: regenerated,
+            content: partial_content.clone(),
+            cancelled: true,
+            usage: Some(usage),
+        }
+        .into(),
+        None => ConversationMessageCancelledEvent {
+            author: crate::models::ChatRole::Assistant,
+            partial_content: partial_content.clone(),
+            usage: Some(usage),
+        }
+        .into(),
+    };
+    conversation_manager
+        .write()
+        .await
+        .add_event_with_id(&conversation_id, message_id, event)?;
+
+    conversation_manager
+        .read()
+        .await
+        .write_to_disk()
+        .map_err(|e| MyError::ConversationWriteToDiskFail {
+            conversation_id: Some(conversation_id),
+            message: e.to_string(),
+        })?;
+
+    app_handle
+        .emit_all(
+            "conversation_message_cancelled",
+            ConversationMessageCancelledEventPayload {
+                conversation_id,
+                message_id,
+                partial_content,
+            },
+        )
+        .map_err(|e| MyError::EmitFail {
+            event: "conversation_message_cancelled".to_string(),
+            message: e.to_string(),
+        })?;
     Ok(())
 }
 
@@ -219,74 +1107,516 @@ pub async fn new_conversation_user_message(
 #[specta::specta]
 pub async fn new_conversation_assistant_message(
     app_handle: tauri::AppHandle,
-    config: State<'_, crate::config::Config>,
-    chatgpt: State<'_, ChatGPT>,
+    chat_provider: State<'_, Arc<dyn ChatProvider>>,
     conversation_manager: State<'_, RwLock<ConversationManager>>,
+    generation_registry: State<'_, GenerationRegistry>,
     conversation_id: &str,
 ) -> Result<(), MyError> {
-    let conversation_id =
-        uuid::Uuid::parse_str(conversation_id).map_err(|_| MyError::UUIDParseFail)?;
+    let conversation_id = parse_uuid(conversation_id)?;
 
-    let response = {
-        let mut mgr = conversation_manager.write().await;
-        let conv = mgr
-            .conversations
-            .get_mut(&conversation_id)
-            .ok_or(MyError::UUIDParseFail)?;
-
-        let mut ai_conversation = conv.into_chatgpt_conversation(chatgpt.inner().clone());
-        // remove the last message from the conversation
-        let ai_prompt = ai_conversation
-            .history
-            .pop()
-            .ok_or(MyError::ConversationEmptyFail)?;
-        let ai_response = ai_conversation
-            .send_message(ai_prompt.content)
-            .await
-            .map_err(|_| MyError::ConversationAIResponseFail)?;
+    let (_generation_guard, abort_registration) = generation_registry.register(conversation_id)?;
+    let message_id = uuid::Uuid::new_v4();
 
-        let response = ai_response.message().content.clone();
-        conv.add_event(ConversationMessageAddedEvent {
+    // Snapshot the history so the lock isn't held while waiting on the model.
+    let (messages, settings) = build_chat_context(&app_handle, conversation_id, false).await?;
+    if messages.is_empty() {
+        return Err(MyError::ConversationEmptyFail { conversation_id });
+    }
+    let config = app_handle.state::<crate::config::Config>();
+    check_spending_limits(
+        &app_handle,
+        Some(conversation_id),
+        &[PlannedRequest::chat(&config, &messages, &settings)],
+    )
+    .await?;
+
+    let reply = stream_assistant_reply(
+        &app_handle,
+        chat_provider.as_ref(),
+        abort_registration,
+        conversation_id,
+        message_id,
+        &messages,
+        &settings,
+    )
+    .await?;
+    let (response, usage) = match reply {
+        AssistantReply::Completed(response, usage) => (response, usage),
+        AssistantReply::Cancelled(partial_content, usage) => {
+            return record_cancelled_reply(
+                &app_handle,
+                &conversation_manager,
+                conversation_id,
+                message_id,
+                None,
+                partial_content,
+                usage,
+            )
+            .await;
+        }
+    };
+
+    conversation_manager.write().await.add_event_with_id(
+        &conversation_id,
+        message_id,
+        ConversationMessageAddedEvent {
             author: crate::models::ChatRole::Assistant,
             content: response.clone(),
-        });
-        response
-    };
+            usage: Some(usage),
+        },
+    )?;
 
     conversation_manager
         .read()
         .await
-        .write_to_disk(&config.conversation_history_save_path)
-        .map_err(|_| MyError::ConversationWriteToDiskFail)?;
+        .write_to_disk()
+        .map_err(|e| MyError::ConversationWriteToDiskFail {
+            conversation_id: Some(conversation_id),
+            message: e.to_string(),
+        })?;
 
     app_handle
         .emit_all(
             "conversation_message_added",
             ConversationMessageAddedEventPayload {
                 conversation_id,
+                message_id,
                 author: crate::models::ChatRole::Assistant,
                 content: response,
             },
         )
-        .map_err(|_| MyError::EmitFail)?;
+        .map_err(|e| MyError::EmitFail {
+            event: "conversation_message_added".to_string(),
+            message: e.to_string(),
+        })?;
+
+    // The reply shouldn't wait on another model call.
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = generate_conversation_title(&app_handle, conversation_id).await {
+            eprintln!("Failed to title conversation {}: {}", conversation_id, e);
+        }
+    });
+
+    Ok(())
+}
+
+/// Replies again to the latest user message, keeping the previous reply as an alternative.
+#[tauri::command]
+#[specta::specta]
+pub async fn regenerate_assistant_message(
+    app_handle: tauri::AppHandle,
+    chat_provider: State<'_, Arc<dyn ChatProvider>>,
+    conversation_manager: State<'_, RwLock<ConversationManager>>,
+    generation_registry: State<'_, GenerationRegistry>,
+    conversation_id: &str,
+) -> Result<(), MyError> {
+    let conversation_id = parse_uuid(conversation_id)?;
+
+    let (_generation_guard, abort_registration) = generation_registry.register(conversation_id)?;
+    let alternative_id = uuid::Uuid::new_v4();
+
+    let message_id = conversation_manager
+        .read()
+        .await
+        .get(&conversation_id)?
+        .get_messages()
+        .pop()
+        .filter(|msg| msg.author == crate::models::ChatRole::Assistant)
+        .ok_or(MyError::NothingToRegenerateFail { conversation_id })?
+        .id;
+    let (messages, settings) = build_chat_context(&app_handle, conversation_id, true).await?;
+    let config = app_handle.state::<crate::config::Config>();
+    check_spending_limits(
+        &app_handle,
+        Some(conversation_id),
+        &[PlannedRequest::chat(&config, &messages, &settings)],
+    )
+    .await?;
+
+    let reply = stream_assistant_reply(
+        &app_handle,
+        chat_provider.as_ref(),
+        abort_registration,
+        conversation_id,
+        alternative_id,
+        &messages,
+        &settings,
+    )
+    .await?;
+    let (response, usage) = match reply {
+        AssistantReply::Completed(response, usage) => (response, usage),
+        AssistantReply::Cancelled(partial_content, usage) => {
+            return record_cancelled_reply(
+                &app_handle,
+                &conversation_manager,
+                conversation_id,
+                alternative_id,
+                Some(message_id),
+                partial_content,
+                usage,
+            )
+            .await;
+        }
+    };
+
+    conversation_manager.write().await.add_event_with_id(
+        &conversation_id,
+        alternative_id,
+        ConversationMessageAlternativeAddedEvent {
+            message_id,
+            content: response.clone(),
+            cancelled: false,
+            usage: Some(usage),
+        },
+    )?;
+
+    conversation_manager
+        .read()
+        .await
+        .write_to_disk()
+        .map_err(|e| MyError::ConversationWriteToDiskFail {
+            conversation_id: Some(conversation_id),
+            message: e.to_string(),
+        })?;
+
+    app_handle
+        .emit_all(
+            "conversation_message_alternative_added",
+            ConversationMessageAlternativeEventPayload {
+                conversation_id,
+                message_id,
+                alternative_id,
+                content: response,
+            },
+        )
+        .map_err(|e| MyError::EmitFail {
+            event: "conversation_message_alternative_added".to_string(),
+            message: e.to_string(),
+        })?;
 
     Ok(())
 }
 
+#[tauri::command]
+#[specta::specta]
+pub async fn select_message_alternative(
+    app_handle: tauri::AppHandle,
+    conversation_manager: State<'_, RwLock<ConversationManager>>,
+    generation_registry: State<'_, GenerationRegistry>,
+    conversation_id: &str,
+    message_id: &str,
+    alternative_id: &str,
+) -> Result<(), MyError> {
+    let conversation_id = parse_uuid(conversation_id)?;
+    let message_id = parse_uuid(message_id)?;
+    let alternative_id = parse_uuid(alternative_id)?;
+
+    // A reply being generated is added to whichever branch is active when it completes.
+    let _generation_guard = generation_registry.lock(conversation_id)?;
+
+    let content = {
+        let mut mgr = conversation_manager.write().await;
+        let conv = mgr.get_mut(&conversation_id)?;
+        let message = conv
+            .get_messages()
+            .into_iter()
+            .find(|msg| msg.id == message_id)
+            .ok_or(MyError::FindByIDFail { id: message_id })?;
+        if message.selected_alternative_id == alternative_id {
+            return Ok(());
+        }
+        let alternative = message
+            .alternatives
+            .into_iter()
+            .find(|alt| alt.id == alternative_id)
+            .ok_or(MyError::FindByIDFail { id: alternative_id })?;
+        conv.add_event(ConversationMessageAlternativeSelectedEvent {
+            message_id,
+            alternative_id,
+        });
+        alternative.content
+    };
+
+    conversation_manager
+        .read()
+        .await
+        .write_to_disk()
+        .map_err(|e| MyError::ConversationWriteToDiskFail {
+            conversation_id: Some(conversation_id),
+            message: e.to_string(),
+        })?;
+
+    app_handle
+        .emit_all(
+            "conversation_message_alternative_selected",
+            ConversationMessageAlternativeEventPayload {
+                conversation_id,
+                message_id,
+                alternative_id,
+                content,
+            },
+        )
+        .map_err(|e| MyError::EmitFail {
+            event: "conversation_message_alternative_selected".to_string(),
+            message: e.to_string(),
+        })?;
+
+    Ok(())
+}
+
+/// Rewrites an earlier user message on a new branch, the messages after the original stay reachable.
+#[tauri::command]
+#[specta::specta]
+pub async fn edit_conversation_user_message(
+    app_handle: tauri::AppHandle,
+    conversation_manager: State<'_, RwLock<ConversationManager>>,
+    generation_registry: State<'_, GenerationRegistry>,
+    conversation_id: &str,
+    message_id: &str,
+    content: &str,
+) -> Result<(), MyError> {
+    let conversation_id = parse_uuid(conversation_id)?;
+    let message_id = parse_uuid(message_id)?;
+    if content.trim().is_empty() {
+        return Err(MyError::EmptyMessageFail { message_id });
+    }
+
+    // A reply being generated is added to whichever branch is active when it completes.
+    let _generation_guard = generation_registry.lock(conversation_id)?;
+
+    let alternative_id = {
+        let mut mgr = conversation_manager.write().await;
+        let conv = mgr.get_mut(&conversation_id)?;
+        let message = conv
+            .get_messages()
+            .into_iter()
+            .find(|msg| msg.id == message_id)
+            .ok_or(MyError::FindByIDFail { id: message_id })?;
+        if message.author != crate::models::ChatRole::User {
+            return Err(MyError::NotAUserMessageFail { message_id });
+        }
+        conv.add_event(ConversationMessageEditedEvent {
+            message_id,
+            content: content.to_string(),
+        })
+        .id
+    };
+
+    conversation_manager
+        .read()
+        .await
+        .write_to_disk()
+        .map_err(|e| MyError::ConversationWriteToDiskFail {
+            conversation_id: Some(conversation_id),
+            message: e.to_string(),
+        })?;
+
+    app_handle
+        .emit_all(
+            "conversation_message_edited",
+            ConversationMessageAlternativeEventPayload {
+                conversation_id,
+                message_id,
+                alternative_id,
+                content: content.to_string(),
+            },
+        )
+        .map_err(|e| MyError::EmitFail {
+            event: "conversation_message_edited".to_string(),
+            message: e.to_string(),
+        })?;
+
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn cancel_assistant_message(
+    generation_registry: State<'_, GenerationRegistry>,
+    conversation_id: &str,
+) -> Result<(), MyError> {
+    let conversation_id = parse_uuid(conversation_id)?;
+    generation_registry.cancel(&conversation_id)
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn list_models(
+    chat_provider: State<'_, Arc<dyn ChatProvider>>,
+) -> Result<Vec<String>, MyError> {
+    chat_provider.list_models().await
+}
+
+/// Totals the tokens used and what they cost, by conversation, model and day.
+#[tauri::command]
+#[specta::specta]
+pub async fn get_usage_report(
+    config: State<'_, crate::config::Config>,
+    conversation_manager: State<'_, RwLock<ConversationManager>>,
+    period: UsagePeriod,
+) -> Result<UsageReport, MyError> {
+    let mgr = conversation_manager.read().await;
+    mgr.usage_report(&period, &config.model_prices)
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn list_templates(
+    template_manager: State<'_, RwLock<TemplateManager>>,
+) -> Result<Vec<PromptTemplate>, MyError> {
+    let mgr = template_manager.read().await;
+    let mut templates: Vec<PromptTemplate> = mgr.templates.values().cloned().collect();
+    templates.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(templates)
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn create_template(
+    template_manager: State<'_, RwLock<TemplateManager>>,
+    config: State<'_, crate::config::Config>,
+    name: &str,
+    body: &str,
+    system_prompt: Option<String>,
+) -> Result<PromptTemplate, MyError> {
+    let template = PromptTemplate::new(name, body, system_prompt.as_deref())?;
+
+    let mut mgr = template_manager.write().await;
+    mgr.templates.insert(template.id, template.clone());
+    mgr.write_to_disk(&config.templates_save_path())
+        .map_err(|e| MyError::TemplateWriteToDiskFail {
+            message: e.to_string(),
+        })?;
+    Ok(template)
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn edit_template(
+    template_manager: State<'_, RwLock<TemplateManager>>,
+    config: State<'_, crate::config::Config>,
+    template_id: &str,
+    name: &str,
+    body: &str,
+    system_prompt: Option<String>,
+) -> Result<PromptTemplate, MyError> {
+    let template_id = parse_uuid(template_id)?;
+
+    let mut mgr = template_manager.write().await;
+    let template = mgr
+        .templates
+        .get_mut(&template_id)
+        .ok_or(MyError::FindByIDFail { id: template_id })?;
+    template.update(name, body, system_prompt.as_deref())?;
+    let template = template.clone();
+    mgr.write_to_disk(&config.templates_save_path())
+        .map_err(|e| MyError::TemplateWriteToDiskFail {
+            message: e.to_string(),
+        })?;
+    Ok(template)
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn delete_template(
+    template_manager: State<'_, RwLock<TemplateManager>>,
+    config: State<'_, crate::config::Config>,
+    template_id: &str,
+) -> Result<(), MyError> {
+    let template_id = parse_uuid(template_id)?;
+
+    let mut mgr = template_manager.write().await;
+    mgr.templates
+        .remove(&template_id)
+        .ok_or(MyError::FindByIDFail { id: template_id })?;
+    mgr.write_to_disk(&config.templates_save_path())
+        .map_err(|e| MyError::TemplateWriteToDiskFail {
+            message: e.to_string(),
+        })?;
+    Ok(())
+}
+
+/// Starts a new conversation titled after the template, holding its rendered system prompt and message.
+#[tauri::command]
+#[specta::specta]
+pub async fn instantiate_template(
+    template_manager: State<'_, RwLock<TemplateManager>>,
+    conversation_manager: State<'_, RwLock<ConversationManager>>,
+    app_handle: tauri::AppHandle,
+    template_id: &str,
+    values: HashMap<String, String>,
+) -> Result<Conversation, MyError> {
+    let template_id = parse_uuid(template_id)?;
+
+    let (name, body, system_prompt) = {
+        let mgr = template_manager.read().await;
+        let template = mgr
+            .templates
+            .get(&template_id)
+            .ok_or(MyError::FindByIDFail { id: template_id })?;
+        let (body, system_prompt) = template.render(&values)?;
+        (template.name.clone(), body, system_prompt)
+    };
+
+    let mut conv = Conversation::new();
+    conv.add_event(ConversationTitleChangedEvent {
+        new_title: name,
+        auto_generated: false,
+        usage: None,
+    });
+    if let Some(system_prompt) = system_prompt {
+        conv.add_event(ConversationSystemPromptChangedEvent {
+            content: system_prompt,
+        });
+    }
+    conv.add_event(ConversationMessageAddedEvent {
+        author: crate::models::ChatRole::User,
+        content: body,
+        usage: None,
+    });
+
+    let mut mgr = conversation_manager.write().await;
+    mgr.insert(conv.clone());
+    mgr.write_to_disk()
+        .map_err(|e| MyError::ConversationWriteToDiskFail {
+            conversation_id: Some(conv.id),
+            message: e.to_string(),
+        })?;
+
+    // Drop the lock before emitting events.
+    drop(mgr);
+
+    app_handle
+        .emit_all(
+            "new_conversation",
+            ConversationAddedEvent {
+                conversation_id: conv.id,
+                title: conv.get_title().into_owned(),
+            },
+        )
+        .map_err(|e| MyError::EmitFail {
+            event: "new_conversation".to_string(),
+            message: e.to_string(),
+        })?;
+    Ok(conv)
+}
+
 #[tauri::command]
 #[specta::specta]
 pub async fn list_files() -> Result<Vec<String>, MyError> {
+    let dir_list_fail = |e: std::io::Error| MyError::DirListFail {
+        path: "./".to_string(),
+        message: e.to_string(),
+    };
     let res = std::fs::read_dir("./")
-        .map_err(|_| MyError::DirListFail)?
+        .map_err(dir_list_fail)?
         .map(|res| res.map(|e| e.path().display().to_string()))
         .collect::<Result<Vec<String>, std::io::Error>>()
-        .map_err(|_| MyError::DirListFail)?;
+        .map_err(dir_list_fail)?;
 
     Ok(res)
 }
 
-
-
 #[cfg(test)]
 mod test {
     use crate::{commands, events};
@@ -296,13 +1626,37 @@ mod test {
         tauri_specta::ts::export_with_cfg(
             specta::collect_types![
                 commands::list_conversation_titles,
+                commands::list_conversation_titles_with_status,
                 commands::get_conversation_messages,
+                commands::get_conversation_history_page,
+                commands::get_conversation_messages_page,
+                commands::search_conversations,
+                commands::semantic_search,
                 commands::get_conversation_title,
                 commands::get_conversation,
                 commands::new_conversation,
                 commands::set_conversation_title,
+                commands::archive_conversation,
+                commands::delete_conversation,
+                commands::restore_conversation,
+                commands::purge_trash,
+                commands::get_conversation_settings,
+                commands::set_conversation_settings,
+                commands::get_system_prompt,
+                commands::set_system_prompt,
                 commands::new_conversation_user_message,
                 commands::new_conversation_assistant_message,
+                commands::regenerate_assistant_message,
+                commands::select_message_alternative,
+                commands::edit_conversation_user_message,
+                commands::cancel_assistant_message,
+                commands::list_models,
+                commands::get_usage_report,
+                commands::list_templates,
+                commands::create_template,
+                commands::edit_template,
+                commands::delete_template,
+                commands::instantiate_template,
                 commands::list_files,
                 events::wrap_event_payloads,
             ]
@@ -1,18 +1,159 @@
 use serde::{Deserialize, Serialize};
 use tauri::api::path::config_dir;
 use std::fs::File;
-use std::io::Write;
 use std::io::{self, Read};
 use std::path::{Path, PathBuf};
-use chatgpt::client::ChatGPT;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
 
 use crate::models::MyError;
+use crate::persistence;
+use crate::providers::{ChatProvider, OpenAICompatibleProvider, RetryPolicy};
+use crate::usage::ModelPrice;
+use crate::schema::{self, CONFIG_MIGRATIONS, CONFIG_VERSION};
 
+const DEFAULT_OPENAI_API_BASE_URL: &str = "https://api.openai.com/v1/";
+const DEFAULT_OPENAI_MODEL: &str = "gpt-3.5-turbo";
+const DEFAULT_TRASH_RETENTION_DAYS: u32 = 30;
+const DEFAULT_MAX_LOADED_CONVERSATIONS: usize = 32;
+const DEFAULT_CONTEXT_WINDOW_TOKENS: u32 = 4096;
+const DEFAULT_SPENDING_WARNING_THRESHOLD: f64 = 0.8;
+const DEFAULT_RETRY_MAX_ATTEMPTS: u32 = 4;
+const DEFAULT_RETRY_INITIAL_BACKOFF_MS: u64 = 500;
+const DEFAULT_RETRY_MAX_BACKOFF_MS: u64 = 30_000;
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 60;
+
+fn default_openai_api_base_url() -> String {
+    DEFAULT_OPENAI_API_BASE_URL.to_string()
+}
+
+fn default_openai_model() -> String {
+    DEFAULT_OPENAI_MODEL.to_string()
+}
+
+fn default_trash_retention_days() -> u32 {
+    DEFAULT_TRASH_RETENTION_DAYS
+}
+
+fn default_max_loaded_conversations() -> usize {
+    DEFAULT_MAX_LOADED_CONVERSATIONS
+}
+
+fn default_context_window_tokens() -> u32 {
+    DEFAULT_CONTEXT_WINDOW_TOKENS
+}
+
+fn default_spending_warning_threshold() -> f64 {
+    DEFAULT_SPENDING_WARNING_THRESHOLD
+}
+
+fn default_retry_max_attempts() -> u32 {
+    DEFAULT_RETRY_MAX_ATTEMPTS
+}
+
+fn default_retry_initial_backoff_ms() -> u64 {
+    DEFAULT_RETRY_INITIAL_BACKOFF_MS
+}
+
+fn default_retry_max_backoff_ms() -> u64 {
+    DEFAULT_RETRY_MAX_BACKOFF_MS
+}
+
+fn default_request_timeout_secs() -> u64 {
+    DEFAULT_REQUEST_TIMEOUT_SECS
+}
+
+fn default_conversation_database_path() -> String {
+    match config_dir() {
+        Some(mut path) => {
+            path.push("ehyaioess");
+            path.push("conversations.sqlite3");
+            path.to_str().unwrap().to_string()
+        }
+        None => String::new(),
+    }
+}
+
+/// How conversations are saved, an existing JSON history is moved into SQLite on first start.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub enum ConversationStorage {
+    /// A snapshot at `conversation_history_save_path` plus an append-only log next to it.
+    Json,
+    /// A database at `conversation_database_path`.
+    #[default]
+    Sqlite,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
+    version: u32,
     openai_api_key: String,
+    /// Any OpenAI-compatible server, e.g. llama.cpp, vLLM or Ollama's `/v1` endpoint.
+    #[serde(default = "default_openai_api_base_url")]
+    openai_api_base_url: String,
+    /// Extra headers sent with every request, for gateways that need their own auth.
+    #[serde(default)]
+    openai_api_headers: HashMap<String, String>,
+    #[serde(default = "default_openai_model")]
+    openai_model: String,
     pub conversation_history_save_path: String,
+    #[serde(default)]
+    pub conversation_storage: ConversationStorage,
+    #[serde(default = "default_conversation_database_path")]
+    pub conversation_database_path: String,
+    /// Where templates are saved, `templates.json` next to the conversation history when unset.
+    #[serde(default)]
+    templates_save_path: Option<String>,
+    /// How long deleted conversations stay in the trash before `purge_trash` removes them.
+    #[serde(default = "default_trash_retention_days")]
+    pub trash_retention_days: u32,
+    /// How many conversation histories are kept in memory, the least recently used are unloaded past that.
+    ///
+    /// The JSON storage can't unload them, so it keeps them all loaded and only warns past this.
+    #[serde(default = "default_max_loaded_conversations")]
+    pub max_loaded_conversations: usize,
+    /// Model the provider computes embeddings with for `semantic_search`, they are computed locally when unset.
+    #[serde(default)]
+    pub embedding_model: Option<String>,
+    /// Model conversations are titled with after their first reply, the chat model when unset.
+    #[serde(default)]
+    pub title_model: Option<String>,
+    /// Tokens a model reads at most, prompt and reply together, for models missing from `model_context_windows`.
+    #[serde(default = "default_context_window_tokens")]
+    pub context_window_tokens: u32,
+    /// Context window of each model by name, e.g. `{"gpt-4": 8192}`.
+    #[serde(default)]
+    pub model_context_windows: HashMap<String, u32>,
+    /// Dollars per million tokens of each model by name, e.g. `{"gpt-4": {"prompt": 30, "completion": 60}}`.
+    #[serde(default)]
+    pub model_prices: HashMap<String, ModelPrice>,
+    /// Dollars that may be spent each calendar month.
+    ///
+    /// While a limit is set, requests to models missing from `model_prices` are refused.
+    #[serde(default)]
+    pub monthly_spending_limit: Option<f64>,
+    /// Dollars that may be spent on a single conversation.
+    #[serde(default)]
+    pub conversation_spending_limit: Option<f64>,
+    /// Caps the reply length of every request, whatever the conversation settings ask for.
+    #[serde(default)]
+    pub max_tokens_per_request: Option<u32>,
+    /// Fraction of a spending limit past which every request warns that it is close.
+    #[serde(default = "default_spending_warning_threshold")]
+    pub spending_warning_threshold: f64,
+    /// Attempts made at a request failing with 429, a 5xx or a timeout before giving up, 1 to never retry.
+    #[serde(default = "default_retry_max_attempts")]
+    pub retry_max_attempts: u32,
+    /// Most the first retry waits, doubling with every retry after it.
+    #[serde(default = "default_retry_initial_backoff_ms")]
+    pub retry_initial_backoff_ms: u64,
+    /// Longest wait between attempts, requests the server asks to retry later than that fail.
+    #[serde(default = "default_retry_max_backoff_ms")]
+    pub retry_max_backoff_ms: u64,
+    /// How long the server may take to start responding before the attempt times out.
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
 }
 
 impl Config {
@@ -32,25 +173,56 @@ impl Config {
         let path = Config::get_config_path()?;
         println!("Config path: {:?}", path);
         if path.exists() {
-            let mut file = File::open(path)?;
+            let mut file = File::open(&path)?;
             let mut contents = String::new();
             file.read_to_string(&mut contents)?;
-            let config: Config = serde_json::from_str(&contents)?;
+            let (config, upgraded) = Config::from_json(&contents)?;
+            if upgraded {
+                persistence::write_pretty_json_atomically(&path, &config)?;
+            }
             Ok(config)
         } else {
             let config = Config::from_user()?;
-            let json = serde_json::to_string_pretty(&config)?;
-            let mut file = File::create(path)?;
-            file.write_all(json.as_bytes())?;
+            persistence::write_pretty_json_atomically(&path, &config)?;
             Ok(config)
         }
     }
 
+    /// Parses a config from any version, also returning whether it had to be migrated.
+    fn from_json(contents: &str) -> Result<(Self, bool), Box<dyn std::error::Error>> {
+        let mut value: serde_json::Value = serde_json::from_str(contents)?;
+        let version = match value.get("version") {
+            Some(version) => schema::parse_version(version)?,
+            None => 0,
+        };
+        schema::migrate(&mut value, version, &CONFIG_MIGRATIONS)?;
+        if let Some(object) = value.as_object_mut() {
+            object.insert("version".to_string(), CONFIG_VERSION.into());
+        }
+        Ok((serde_json::from_value(value)?, version < CONFIG_VERSION))
+    }
+
     fn from_user() -> Result<Self, Box<dyn std::error::Error>> {
         println!("Please enter your OpenAI API Key: ");
         let mut openai_api_key = String::new();
         io::stdin().read_line(&mut openai_api_key)?;
 
+        let mut openai_api_base_url = default_openai_api_base_url();
+        println!("Please enter the OpenAI-compatible API base URL (leave blank for {}): ", DEFAULT_OPENAI_API_BASE_URL);
+        let mut user_input = String::new();
+        io::stdin().read_line(&mut user_input)?;
+        if user_input.trim().len() > 0 {
+            openai_api_base_url = user_input.trim().to_string();
+        }
+
+        let mut openai_model = default_openai_model();
+        println!("Please enter the model to use (leave blank for {}): ", DEFAULT_OPENAI_MODEL);
+        let mut user_input = String::new();
+        io::stdin().read_line(&mut user_input)?;
+        if user_input.trim().len() > 0 {
+            openai_model = user_input.trim().to_string();
+        }
+
         let mut conversation_history_save_path = match config_dir() {
             Some(mut path) => {
                 path.push("ehyaioess");
@@ -69,13 +241,94 @@ impl Config {
         }
 
         Ok(Config {
+            version: CONFIG_VERSION,
             openai_api_key: openai_api_key.trim().to_string(),
+            openai_api_base_url,
+            openai_api_headers: HashMap::new(),
+            openai_model,
             conversation_history_save_path,
+            conversation_storage: ConversationStorage::default(),
+            conversation_database_path: default_conversation_database_path(),
+            templates_save_path: None,
+            trash_retention_days: DEFAULT_TRASH_RETENTION_DAYS,
+            max_loaded_conversations: DEFAULT_MAX_LOADED_CONVERSATIONS,
+            embedding_model: None,
+            title_model: None,
+            context_window_tokens: DEFAULT_CONTEXT_WINDOW_TOKENS,
+            model_context_windows: HashMap::new(),
+            model_prices: HashMap::new(),
+            monthly_spending_limit: None,
+            conversation_spending_limit: None,
+            max_tokens_per_request: None,
+            spending_warning_threshold: DEFAULT_SPENDING_WARNING_THRESHOLD,
+            retry_max_attempts: DEFAULT_RETRY_MAX_ATTEMPTS,
+            retry_initial_backoff_ms: DEFAULT_RETRY_INITIAL_BACKOFF_MS,
+            retry_max_backoff_ms: DEFAULT_RETRY_MAX_BACKOFF_MS,
+            request_timeout_secs: DEFAULT_REQUEST_TIMEOUT_SECS,
         })
     }
 
-    pub fn create_chatgpt_client(&self) -> Result<ChatGPT, Box<dyn std::error::Error>> {
-        let client: ChatGPT = ChatGPT::new(&self.openai_api_key)?;
-        Ok(client)
+    pub fn templates_save_path(&self) -> String {
+        match &self.templates_save_path {
+            Some(path) => path.clone(),
+            None => Path::new(&self.conversation_history_save_path)
+                .with_file_name("templates.json")
+                .to_string_lossy()
+                .into_owned(),
+        }
     }
-}
\ No newline at end of file
+
+    /// The model replying to conversations with `model` in their settings.
+    pub fn chat_model<'a>(&'a self, model: Option<&'a str>) -> &'a str {
+        model.unwrap_or(&self.openai_model)
+    }
+
+    /// Context window of `model`, the configured chat model when `None`.
+    pub fn context_window(&self, model: Option<&str>) -> u32 {
+        self.model_context_windows
+            .get(self.chat_model(model))
+            .copied()
+            .unwrap_or(self.context_window_tokens)
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.retry_max_attempts.max(1),
+            initial_backoff: Duration::from_millis(self.retry_initial_backoff_ms),
+            max_backoff: Duration::from_millis(self.retry_max_backoff_ms),
+            timeout: Duration::from_secs(self.request_timeout_secs),
+        }
+    }
+
+    pub fn create_chat_provider(&self) -> Result<Arc<dyn ChatProvider>, Box<dyn std::error::Error>> {
+        let provider = OpenAICompatibleProvider::new(
+            &self.openai_api_base_url,
+            &self.openai_api_key,
+            &self.openai_api_headers,
+            &self.openai_model,
+            self.retry_policy(),
+        )?;
+        Ok(Arc::new(provider))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_config_fixtures() {
+        let (config, upgraded) =
+            Config::from_json(include_str!("../fixtures/config_v0.json")).unwrap();
+        assert!(upgraded);
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.openai_model, DEFAULT_OPENAI_MODEL);
+        assert_eq!(config.trash_retention_days, DEFAULT_TRASH_RETENTION_DAYS);
+
+        let (config, upgraded) =
+            Config::from_json(include_str!("../fixtures/config_v1.json")).unwrap();
+        assert!(!upgraded);
+        assert_eq!(config.openai_model, "llama-3");
+        assert_eq!(config.trash_retention_days, 7);
+    }
+}
@@ -0,0 +1,206 @@
+use uuid::Uuid;
+
+use crate::{
+    models::{ChatRole, ConversationMessage, MyError},
+    providers::ChatMessage,
+};
+
+/// Tokens kept free for the reply when the conversation settings don't cap it.
+pub const DEFAULT_REPLY_TOKENS: u32 = 1024;
+/// Most tokens a summary of the earlier turns may take.
+pub const MAX_SUMMARY_TOKENS: u32 = 300;
+/// Tokens every message costs on top of its content, for its role and separators.
+const MESSAGE_OVERHEAD_TOKENS: u32 = 4;
+const SUMMARY_PROMPT: &str = "Summarize the conversation below in a few sentences, keeping the \
+    names, facts and decisions later messages may refer back to. Reply with the summary only.";
+
+/// Estimated token count of a message.
+///
+/// Tokenizers differ between models, so this errs on the high side with three characters per
+/// token where English text averages about four.
+pub fn estimate_tokens(message: &ChatMessage) -> u32 {
+    MESSAGE_OVERHEAD_TOKENS + estimate_text_tokens(&message.content)
+}
+
+/// Estimated token count of text sent on its own, like the input of an embedding.
+pub fn estimate_text_tokens(text: &str) -> u32 {
+    (text.chars().count() as u32).div_ceil(3)
+}
+
+/// Index of the first of the latest items whose costs add up to at most `limit`.
+fn first_fitting(costs: &[u32], limit: u32) -> usize {
+    let mut total: u32 = 0;
+    let mut first = costs.len();
+    for (i, cost) in costs.iter().enumerate().rev() {
+        total = total.saturating_add(*cost);
+        if total > limit {
+            break;
+        }
+        first = i;
+    }
+    first
+}
+
+/// Messages to send to the model, fitted into its context window.
+#[derive(Debug)]
+pub struct ChatContext {
+    pub messages: Vec<ChatMessage>,
+    /// Set when older turns were left out that no summary covers yet.
+    pub summary_request: Option<SummaryRequest>,
+}
+
+/// Asks for a summary of the turns left out of the context, folding in the previous summary.
+#[derive(Debug)]
+pub struct SummaryRequest {
+    pub messages: Vec<ChatMessage>,
+    /// Variant id of the latest message the summary will cover.
+    pub through_alternative_id: Uuid,
+}
+
+/// Pins the system prompt and the summary of earlier turns, then fills the rest of `budget` with
+/// the latest `messages`.
+///
+/// Once turns have to be left out, enough of them are left out for the kept ones to fit in half
+/// the budget, so a summary of them lasts for a few more replies. Left out turns that don't fit
+/// in the summary request either are dropped without being summarized.
+pub fn build_context(
+    system_prompt: Option<&str>,
+    summary: Option<&str>,
+    messages: &[ConversationMessage],
+    budget: u32,
+) -> Result<ChatContext, MyError> {
+    let system_prompt = system_prompt
+        .filter(|prompt| !prompt.trim().is_empty())
+        .map(|prompt| ChatMessage {
+            role: ChatRole::System,
+            content: prompt.to_string(),
+        });
+    let summary_message = summary.map(|summary| ChatMessage {
+        role: ChatRole::System,
+        content: format!("Summary of the earlier conversation: {}", summary),
+    });
+    let pinned: Vec<ChatMessage> = system_prompt.into_iter().chain(summary_message).collect();
+    let available = budget.saturating_sub(pinned.iter().map(estimate_tokens).sum());
+
+    let history: Vec<ChatMessage> = messages
+        .iter()
+        .map(|message| ChatMessage {
+            role: message.author,
+            content: message.content.clone(),
+        })
+        .collect();
+    let costs: Vec<u32> = history.iter().map(estimate_tokens).collect();
+    let mut first_kept = first_fitting(&costs, available);
+    if first_kept == history.len() && !history.is_empty() {
+        return Err(MyError::ContextWindowExceededFail {
+            tokens: costs[history.len() - 1],
+            available,
+        });
+    }
+
+    let mut summary_request = None;
+    if first_kept > 0 {
+        first_kept = first_fitting(&costs, available / 2).min(history.len() - 1);
+        let left_out = &history[..first_kept];
+        let left_out = &left_out[first_fitting(&costs[..first_kept], available)..];
+        let transcript: String = summary
+            .map(|summary| format!("Earlier summary: {}\n", summary))
+            .into_iter()
+            .chain(
+                left_out
+                    .iter()
+                    .map(|message| format!("{:?}: {}\n", message.role, message.content)),
+            )
+            .collect();
+        summary_request = Some(SummaryRequest {
+            messages: vec![
+                ChatMessage {
+                    role: ChatRole::System,
+                    content: SUMMARY_PROMPT.to_string(),
+                },
+                ChatMessage {
+                    role: ChatRole::User,
+                    content: transcript,
+                },
+            ],
+            through_alternative_id: messages[first_kept - 1].selected_alternative_id,
+        });
+    }
+
+    Ok(ChatContext {
+        messages: pinned
+            .into_iter()
+            .chain(history.into_iter().skip(first_kept))
+            .collect(),
+        summary_request,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::{
+        Conversation, ConversationContextSummarizedEvent, ConversationMessageAddedEvent,
+        ConversationSystemPromptChangedEvent,
+    };
+
+    #[test]
+    fn test_build_context() {
+        let mut conv = Conversation::new();
+        conv.add_event(ConversationSystemPromptChangedEvent {
+            content: "Be brief.".to_string(),
+        });
+        for i in 0..10 {
+            conv.add_event(ConversationMessageAddedEvent {
+                author: if i % 2 == 0 {
+                    ChatRole::User
+                } else {
+                    ChatRole::Assistant
+                },
+                content: format!("Message {} {}", i, "x".repeat(50)),
+                usage: None,
+            });
+        }
+        let branch = conv.get_messages();
+        let cost = estimate_tokens(&ChatMessage {
+            role: ChatRole::User,
+            content: branch[0].content.clone(),
+        });
+
+        // Everything fits
+        let context = conv.build_context(&branch, 1000).unwrap();
+        assert_eq!(context.messages.len(), 11);
+        assert!(context.summary_request.is_none());
+
+        // The system prompt stays, older turns are left out down to half the budget
+        let context = conv.build_context(&branch, 8 * cost).unwrap();
+        assert_eq!(context.messages[0].content, "Be brief.");
+        assert_eq!(context.messages.len(), 4);
+        assert!(context.messages[3].content.starts_with("Message 9"));
+        let request = context.summary_request.unwrap();
+        assert_eq!(
+            request.through_alternative_id,
+            branch[6].selected_alternative_id
+        );
+        assert!(request.messages[1].content.contains("Message 6"));
+
+        // The recorded summary is pinned after the system prompt
+        conv.add_event(ConversationContextSummarizedEvent {
+            summary: "Counting messages.".to_string(),
+            through_alternative_id: request.through_alternative_id,
+            usage: None,
+        });
+        let context = conv.build_context(&branch, 8 * cost).unwrap();
+        assert!(context.summary_request.is_none());
+        assert_eq!(
+            context.messages[1].content,
+            "Summary of the earlier conversation: Counting messages."
+        );
+        assert_eq!(context.messages.len(), 5);
+
+        assert!(matches!(
+            conv.build_context(&branch, cost / 2),
+            Err(MyError::ContextWindowExceededFail { tokens, .. }) if tokens == cost
+        ));
+    }
+}
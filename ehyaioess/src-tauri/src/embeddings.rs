@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use uuid::Uuid;
+
+use crate::{
+    models::MyError,
+    providers::{ChatProvider, Embeddings},
+    search::{tokenize, SearchResult},
+};
+
+/// Model name the locally computed vectors are saved under.
+pub const LOCAL_EMBEDDING_MODEL: &str = "local-hashed-tf-idf";
+/// Number of buckets words are hashed into by the local embeddings.
+const LOCAL_DIMENSIONS: usize = 512;
+/// Most inputs sent to the provider in a single request.
+pub const EMBEDDING_BATCH_SIZE: usize = 64;
+
+/// The vector of a message variant, tagged with its model so vectors of different models are never compared.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StoredEmbedding {
+    pub event_id: Uuid,
+    pub conversation_id: Uuid,
+    pub model: String,
+    pub vector: Vec<f32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct SemanticSearchResult {
+    /// The message of the conversation closest to the query.
+    pub result: SearchResult,
+    /// Cosine similarity with the query.
+    pub score: f32,
+}
+
+/// FNV-1a, which unlike the std hasher is guaranteed to stay the same across releases.
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Sublinear term frequencies of the words of `text`, hashed into a fixed number of buckets.
+///
+/// Inverse document frequencies change with every message, so they are applied at search time.
+pub fn embed_locally(text: &str) -> Vec<f32> {
+    let mut counts: HashMap<String, u32> = HashMap::new();
+    for (token, _, _) in tokenize(text) {
+        *counts.entry(token).or_default() += 1;
+    }
+    let mut vector = vec![0.0; LOCAL_DIMENSIONS];
+    for (token, count) in counts {
+        vector[(fnv1a(&token) % LOCAL_DIMENSIONS as u64) as usize] += 1.0 + (count as f32).ln();
+    }
+    vector
+}
+
+/// Embeds the texts with the provider's `model`, or locally without one.
+///
+/// The prompt tokens add up those of every batch, and are `None` for local embeddings.
+pub async fn embed(
+    provider: &dyn ChatProvider,
+    model: Option<&str>,
+    texts: &[String],
+) -> Result<Embeddings, MyError> {
+    let Some(model) = model else {
+        return Ok(Embeddings {
+            vectors: texts.iter().map(|text| embed_locally(text)).collect(),
+            prompt_tokens: None,
+        });
+    };
+    let mut embeddings = Embeddings {
+        vectors: Vec::with_capacity(texts.len()),
+        prompt_tokens: Some(0),
+    };
+    for batch in texts.chunks(EMBEDDING_BATCH_SIZE) {
+        let batch = provider.embed(model, batch).await?;
+        embeddings.vectors.extend(batch.vectors);
+        embeddings.prompt_tokens = embeddings
+            .prompt_tokens
+            .zip(batch.prompt_tokens)
+            .map(|(total, tokens)| total + tokens);
+    }
+    Ok(embeddings)
+}
+
+/// Cosine similarity, with each dimension scaled by its weight when there are any.
+fn cosine(a: &[f32], b: &[f32], weights: Option<&[f32]>) -> f32 {
+    let (mut dot, mut a_norm, mut b_norm) = (0.0, 0.0, 0.0);
+    for i in 0..a.len() {
+        let weight = weights.map_or(1.0, |weights| weights[i] * weights[i]);
+        dot += a[i] * b[i] * weight;
+        a_norm += a[i] * a[i] * weight;
+        b_norm += b[i] * b[i] * weight;
+    }
+    if a_norm == 0.0 || b_norm == 0.0 {
+        return 0.0;
+    }
+    dot / (a_norm.sqrt() * b_norm.sqrt())
+}
+
+/// Every stored embedding, searched by brute force.
+pub struct EmbeddingIndex {
+    /// Keyed by event id and model, a variant has one vector per model it was embedded with.
+    embeddings: HashMap<(Uuid, String), StoredEmbedding>,
+    /// How many local embeddings have each bucket set.
+    document_frequencies: Vec<u32>,
+    local_count: u32,
+}
+impl EmbeddingIndex {
+    pub fn new() -> Self {
+        Self {
+            embeddings: HashMap::new(),
+            document_frequencies: vec![0; LOCAL_DIMENSIONS],
+            local_count: 0,
+        }
+    }
+    fn count(&mut self, embedding: &StoredEmbedding, added: bool) {
+        if embedding.model != LOCAL_EMBEDDING_MODEL {
+            return;
+        }
+        let change = |count: &mut u32| {
+            *count = if added { *count + 1 } else { *count - 1 };
+        };
+        change(&mut self.local_count);
+        for (i, value) in embedding.vector.iter().enumerate() {
+            if *value != 0.0 {
+                change(&mut self.document_frequencies[i]);
+            }
+        }
+    }
+    pub fn insert(&mut self, embedding: StoredEmbedding) {
+        self.count(&embedding, true);
+        let key = (embedding.event_id, embedding.model.clone());
+        if let Some(previous) = self.embeddings.insert(key, embedding) {
+            self.count(&previous, false);
+        }
+    }
+    pub fn remove_conversation(&mut self, conversation_id: &Uuid) {
+        let removed: Vec<(Uuid, String)> = self
+            .embeddings
+            .iter()
+            .filter(|(_, embedding)| embedding.conversation_id == *conversation_id)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in removed {
+            let embedding = self.embeddings.remove(&key).unwrap();
+            self.count(&embedding, false);
+        }
+    }
+    pub fn contains(&self, event_id: &Uuid, model: &str) -> bool {
+        self.embeddings
+            .contains_key(&(*event_id, model.to_string()))
+    }
+    /// The `top_k` conversations closest to the query as `(event id, score)`, with their closest message.
+    pub fn search(&self, query: &[f32], model: &str, top_k: usize) -> Vec<(Uuid, f32)> {
+        let idf: Option<Vec<f32>> = (model == LOCAL_EMBEDDING_MODEL).then(|| {
+            self.document_frequencies
+                .iter()
+                .map(|df| ((1.0 + self.local_count as f32) / (1.0 + *df as f32)).ln() + 1.0)
+                .collect()
+        });
+        let mut best: HashMap<Uuid, (Uuid, f32)> = HashMap::new();
+        for embedding in self.embeddings.values() {
+            if embedding.model != model || embedding.vector.len() != query.len() {
+                continue;
+            }
+            let score = cosine(query, &embedding.vector, idf.as_deref());
+            let entry = best
+                .entry(embedding.conversation_id)
+                .or_insert((embedding.event_id, score));
+            if score > entry.1 {
+                *entry = (embedding.event_id, score);
+            }
+        }
+        let mut results: Vec<(Uuid, f32)> = best.into_values().collect();
+        results.sort_by(|a, b| b.1.total_cmp(&a.1));
+        results.truncate(top_k);
+        results
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_local_semantic_search() {
+        let mut index = EmbeddingIndex::new();
+        let conversations = [Uuid::new_v4(), Uuid::new_v4()];
+        let texts = [
+            (
+                conversations[0],
+                "The borrow checker rejects two mutable borrows",
+            ),
+            (
+                conversations[0],
+                "Lifetimes tie references to the data they borrow",
+            ),
+            (
+                conversations[1],
+                "A recipe for sourdough bread with a long rise",
+            ),
+        ];
+        let mut ids = Vec::new();
+        for (conversation_id, text) in texts {
+            let event_id = Uuid::new_v4();
+            ids.push(event_id);
+            index.insert(StoredEmbedding {
+                event_id,
+                conversation_id,
+                model: LOCAL_EMBEDDING_MODEL.to_string(),
+                vector: embed_locally(text),
+            });
+        }
+        assert!(index.contains(&ids[0], LOCAL_EMBEDDING_MODEL));
+        assert!(!index.contains(&ids[0], "text-embedding-3-small"));
+
+        // Another model's vector is kept alongside, not in place of the local one
+        index.insert(StoredEmbedding {
+            event_id: ids[0],
+            conversation_id: conversations[0],
+            model: "text-embedding-3-small".to_string(),
+            vector: vec![1.0, 0.0],
+        });
+        assert!(index.contains(&ids[0], LOCAL_EMBEDDING_MODEL));
+        assert!(index.contains(&ids[0], "text-embedding-3-small"));
+        assert_eq!(index.local_count, 3);
+
+        // One result per conversation, with its closest message
+        let results = index.search(
+            &embed_locally("why does the borrow checker complain"),
+            LOCAL_EMBEDDING_MODEL,
+            5,
+        );
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, ids[0]);
+        assert!(results[0].1 > results[1].1);
+
+        index.remove_conversation(&conversations[0]);
+        let results = index.search(&embed_locally("bread"), LOCAL_EMBEDDING_MODEL, 5);
+        assert_eq!(results, vec![(ids[2], results[0].1)]);
+        assert_eq!(index.local_count, 1);
+    }
+}
@@ -1,30 +1,121 @@
-use serde::{Serialize, Deserialize};
-use crate::models::{ChatRole, MyError};
+use crate::models::{ChatRole, ConversationSettings, ConversationStatus, MyError, SpendingLimit};
+use serde::{Deserialize, Serialize};
 use specta::Type;
 
 #[derive(Debug, Serialize, Deserialize, Clone, Type)]
 pub struct ConversationTitleChangedEventPayload {
     pub conversation_id: uuid::Uuid,
     pub new_title: String,
+    pub auto_generated: bool,
 }
 
-
 #[derive(Debug, Serialize, Deserialize, Clone, Type)]
 pub struct ConversationMessageAddedEventPayload {
     pub conversation_id: uuid::Uuid,
+    pub message_id: uuid::Uuid,
     pub author: ChatRole,
     pub content: String,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationMessageDeltaEventPayload {
+    pub conversation_id: uuid::Uuid,
+    pub message_id: uuid::Uuid,
+    pub chunk: String,
+    pub sequence: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationMessageCancelledEventPayload {
+    pub conversation_id: uuid::Uuid,
+    pub message_id: uuid::Uuid,
+    pub partial_content: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationSettingsChangedEventPayload {
+    pub conversation_id: uuid::Uuid,
+    pub settings: ConversationSettings,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationSystemPromptChangedEventPayload {
+    pub conversation_id: uuid::Uuid,
+    pub content: String,
+}
+
+/// Sent when a message variant is added, edited in or selected, `content` is the now selected variant.
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationMessageAlternativeEventPayload {
+    pub conversation_id: uuid::Uuid,
+    pub message_id: uuid::Uuid,
+    pub alternative_id: uuid::Uuid,
+    pub content: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationStatusChangedEventPayload {
+    pub conversation_id: uuid::Uuid,
+    pub status: ConversationStatus,
+    pub title: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationPurgedEventPayload {
+    pub conversation_id: uuid::Uuid,
+}
+
+/// Sent when older turns were summarized to fit the conversation into the model's context window.
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationContextSummarizedEventPayload {
+    pub conversation_id: uuid::Uuid,
+    pub summary: String,
+    pub through_alternative_id: uuid::Uuid,
+}
+
+/// Sent before a request that takes spending past the warning threshold of a limit.
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct SpendingLimitWarningEventPayload {
+    /// `None` for requests made outside of a conversation, like embeddings.
+    pub conversation_id: Option<uuid::Uuid>,
+    pub limit: SpendingLimit,
+    /// Dollars spent so far, plus the most the request can cost.
+    pub spent: f64,
+    pub cap: f64,
+}
+
+/// Sent when a request for the reply failed for a transient reason and is about to be retried.
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct AssistantRetryingEventPayload {
+    pub conversation_id: uuid::Uuid,
+    pub message_id: uuid::Uuid,
+    /// The attempt that failed, starting at 1.
+    pub attempt: u32,
+    pub max_attempts: u32,
+    pub delay_ms: u32,
+    pub reason: String,
+}
+
 #[derive(Type, Serialize, Deserialize, Debug, Clone)]
 pub enum WrapType {
     None,
     ConversationTitleChangedEventPayload(ConversationTitleChangedEventPayload),
     ConversationMessageAddedEventPayload(ConversationMessageAddedEventPayload),
+    ConversationMessageDeltaEventPayload(ConversationMessageDeltaEventPayload),
+    ConversationMessageCancelledEventPayload(ConversationMessageCancelledEventPayload),
+    ConversationSettingsChangedEventPayload(ConversationSettingsChangedEventPayload),
+    ConversationSystemPromptChangedEventPayload(ConversationSystemPromptChangedEventPayload),
+    ConversationMessageAlternativeEventPayload(ConversationMessageAlternativeEventPayload),
+    ConversationStatusChangedEventPayload(ConversationStatusChangedEventPayload),
+    ConversationPurgedEventPayload(ConversationPurgedEventPayload),
+    ConversationContextSummarizedEventPayload(ConversationContextSummarizedEventPayload),
+    SpendingLimitWarningEventPayload(SpendingLimitWarningEventPayload),
+    AssistantRetryingEventPayload(AssistantRetryingEventPayload),
+    MyError(MyError),
 }
 
 #[tauri::command]
 #[specta::specta]
 pub fn wrap_event_payloads(_bruh: WrapType) -> Result<(), MyError> {
     Ok(())
-} 
\ No newline at end of file
+}
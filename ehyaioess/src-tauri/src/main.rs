@@ -3,7 +3,9 @@
 
 mod config;
 use config::Config;
-use models::ConversationManager;
+use models::{ConversationManager, GenerationRegistry};
+use templates::TemplateManager;
+use std::io::ErrorKind;
 use std::time::{Duration, Instant};
 use tauri::{async_runtime::RwLock, Manager};
 use tauri_plugin_window_state::{AppHandleExt, StateFlags};
@@ -11,6 +13,15 @@ use tauri_plugin_window_state::{AppHandleExt, StateFlags};
 mod commands;
 mod models;
 mod events;
+mod providers;
+mod templates;
+mod persistence;
+mod storage;
+mod schema;
+mod search;
+mod embeddings;
+mod context;
+mod usage;
 
 fn main() {
     let config = match Config::from_disk() {
@@ -20,31 +31,79 @@ fn main() {
             std::process::exit(1);
         }
     };
-    let chatgpt = match config.create_chatgpt_client() {
-        Ok(client) => client,
+    let chat_provider = match config.create_chat_provider() {
+        Ok(provider) => provider,
         Err(e) => {
-            eprintln!("Failed to create ChatGPT client: {}", e);
+            eprintln!("Failed to create chat provider: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // Failing to load must stop here, anything unreadable would be overwritten on the next save.
+    let conversation_manager = match storage::open_conversation_store(&config).and_then(|store| {
+        ConversationManager::from_disk(store, config.max_loaded_conversations)
+    }) {
+        Ok(mgr) => mgr,
+        Err(e) => {
+            eprintln!("Failed to load conversation history: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // Only a missing file means there are no templates yet.
+    let templates_save_path = config.templates_save_path();
+    let template_manager = match TemplateManager::from_disk(&templates_save_path) {
+        Ok(mgr) => mgr,
+        Err(e) if e.kind() == ErrorKind::NotFound => TemplateManager::new(),
+        Err(e) => {
+            eprintln!(
+                "Failed to load templates from {}: {}\nThe previous version is kept at {:?}",
+                templates_save_path,
+                e,
+                persistence::backup_path(&templates_save_path),
+            );
             std::process::exit(1);
         }
     };
-    let conversation_manager =
-        ConversationManager::from_disk(&config.conversation_history_save_path)
-            .unwrap_or_else(|_| ConversationManager::new());
 
     tauri::Builder::default()
         .manage(config)
-        .manage(chatgpt)
+        .manage(chat_provider)
         .manage(RwLock::new(conversation_manager))
+        .manage(GenerationRegistry::new())
+        .manage(RwLock::new(template_manager))
         .plugin(tauri_plugin_window_state::Builder::default().build())
         .invoke_handler(tauri::generate_handler![
             commands::list_conversation_titles,
+            commands::list_conversation_titles_with_status,
             commands::get_conversation_messages,
+            commands::get_conversation_history_page,
+            commands::get_conversation_messages_page,
+            commands::search_conversations,
+            commands::semantic_search,
             commands::get_conversation_title,
             commands::get_conversation,
             commands::new_conversation,
             commands::set_conversation_title,
+            commands::archive_conversation,
+            commands::delete_conversation,
+            commands::restore_conversation,
+            commands::purge_trash,
+            commands::get_conversation_settings,
+            commands::set_conversation_settings,
+            commands::get_system_prompt,
+            commands::set_system_prompt,
             commands::new_conversation_user_message,
             commands::new_conversation_assistant_message,
+            commands::regenerate_assistant_message,
+            commands::select_message_alternative,
+            commands::edit_conversation_user_message,
+            commands::cancel_assistant_message,
+            commands::list_models,
+            commands::get_usage_report,
+            commands::list_templates,
+            commands::create_template,
+            commands::edit_template,
+            commands::delete_template,
+            commands::instantiate_template,
             commands::list_files,
         ])
         .setup(|app| {
@@ -1,91 +1,446 @@
 use core::fmt;
 use std::{
     any::TypeId,
-    collections::HashMap, borrow::Cow,
+    collections::HashMap, borrow::Cow, ops::Range, sync::{Arc, Mutex},
 };
 
-use chatgpt::{prelude::ChatGPT, types::ChatMessage};
+use futures_util::future::{AbortHandle, AbortRegistration};
 use serde::{Serialize, Serializer, Deserialize, Deserializer};
 use serde::de::Error;
 use uuid::Uuid;
 use specta::Type;
 
+use crate::context::ChatContext;
+use crate::providers::ChatMessage;
+use crate::embeddings::{EmbeddingIndex, SemanticSearchResult, StoredEmbedding};
+use crate::search::{self, SearchFilters, SearchResult};
+use crate::storage::{ConversationChange, ConversationStore};
+use crate::usage::{self, CompletionUsage, ModelPrice, UsagePeriod, UsageRecord, UsageReport};
+
+/// Errors returned by commands, each variant carries what the frontend needs to explain it.
 #[derive(Debug, Serialize, Deserialize, Clone, Type)]
 pub enum MyError {
-    UUIDParseFail,
-    FindByIDFail,
-    EmitFail,
-    ConversationWriteToDiskFail,
+    UUIDParseFail {
+        value: String,
+    },
+    FindByIDFail {
+        id: Uuid,
+    },
+    EmitFail {
+        event: String,
+        message: String,
+    },
+    ConversationWriteToDiskFail {
+        /// Missing when the write wasn't made for one conversation.
+        conversation_id: Option<Uuid>,
+        message: String,
+    },
     NoConfigDirFail,
-    UserNotLatestAuthorInConversationFail,
-    ConversationEmptyFail,
-    ConversationAIResponseFail,
-    DirListFail,
+    UserNotLatestAuthorInConversationFail {
+        conversation_id: Uuid,
+    },
+    ConversationEmptyFail {
+        conversation_id: Uuid,
+    },
+    ConversationAIResponseFail(ProviderError),
+    GenerationInProgressFail {
+        conversation_id: Uuid,
+    },
+    NoGenerationInProgressFail {
+        conversation_id: Uuid,
+    },
+    ListModelsFail(ProviderError),
+    InvalidSettingsFail {
+        /// Name of the first setting out of range.
+        setting: String,
+    },
+    InvalidTemplateFail {
+        /// `name` or `body`, whichever is empty.
+        field: String,
+    },
+    TemplateVariableMissingFail {
+        name: String,
+    },
+    TemplateWriteToDiskFail {
+        message: String,
+    },
+    NothingToRegenerateFail {
+        conversation_id: Uuid,
+    },
+    NotAUserMessageFail {
+        message_id: Uuid,
+    },
+    EmptyMessageFail {
+        message_id: Uuid,
+    },
+    ConversationReadFromDiskFail {
+        conversation_id: Uuid,
+        message: String,
+    },
+    UsageReadFromDiskFail {
+        message: String,
+    },
+    PageCursorNotFoundFail {
+        cursor: Uuid,
+    },
+    SearchFail {
+        message: String,
+    },
+    EmbeddingFail(ProviderError),
+    ContextWindowExceededFail {
+        /// Estimated tokens of the latest message.
+        tokens: u32,
+        /// Tokens left for it once the system prompt, summary and reply are accounted for.
+        available: u32,
+    },
+    SpendingLimitExceededFail {
+        limit: SpendingLimit,
+        /// Dollars spent so far in the period the limit covers.
+        spent: f64,
+        cap: f64,
+    },
+    /// A spending limit applies but the model has no price, so the request's cost is unknown.
+    UnpricedModelFail {
+        model: String,
+    },
+    DirListFail {
+        path: String,
+        message: String,
+    },
 }
 impl fmt::Display for MyError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            MyError::UUIDParseFail => write!(f, "Failed to parse UUID"),
-            MyError::FindByIDFail => write!(f, "Failed to find by ID"),
-            MyError::EmitFail => write!(f, "Failed to emit"),
-            MyError::ConversationWriteToDiskFail => {
-                write!(f, "Failed to write conversation to disk")
+        match self {
+            MyError::UUIDParseFail { value } => write!(f, "Failed to parse UUID {:?}", value),
+            MyError::FindByIDFail { id } => write!(f, "Failed to find {}", id),
+            MyError::EmitFail { event, message } => {
+                write!(f, "Failed to emit {}: {}", event, message)
             }
+            MyError::ConversationWriteToDiskFail {
+                conversation_id: Some(conversation_id),
+                message,
+            } => write!(
+                f,
+                "Failed to write conversation {} to disk: {}",
+                conversation_id, message
+            ),
+            MyError::ConversationWriteToDiskFail {
+                conversation_id: None,
+                message,
+            } => write!(f, "Failed to write conversations to disk: {}", message),
             MyError::NoConfigDirFail => write!(f, "Failed identifying config directory"),
-            MyError::UserNotLatestAuthorInConversationFail => {
-                write!(f, "User is not the latest author in the conversation")
+            MyError::UserNotLatestAuthorInConversationFail { conversation_id } => write!(
+                f,
+                "User is not the latest author in conversation {}",
+                conversation_id
+            ),
+            MyError::ConversationEmptyFail { conversation_id } => {
+                write!(f, "Conversation {} is empty", conversation_id)
+            }
+            MyError::ConversationAIResponseFail(e) => write!(f, "Failed to get AI response: {}", e),
+            MyError::GenerationInProgressFail { conversation_id } => write!(
+                f,
+                "An assistant message is already being generated in conversation {}",
+                conversation_id
+            ),
+            MyError::NoGenerationInProgressFail { conversation_id } => write!(
+                f,
+                "No assistant message is being generated in conversation {}",
+                conversation_id
+            ),
+            MyError::ListModelsFail(e) => write!(f, "Failed to list models: {}", e),
+            MyError::InvalidSettingsFail { setting } => {
+                write!(f, "Conversation setting {} is out of range", setting)
+            }
+            MyError::InvalidTemplateFail { field } => {
+                write!(f, "Template {} must not be empty", field)
+            }
+            MyError::TemplateVariableMissingFail { name } => {
+                write!(f, "No value given for template variable {}", name)
+            }
+            MyError::TemplateWriteToDiskFail { message } => {
+                write!(f, "Failed to write templates to disk: {}", message)
+            }
+            MyError::NothingToRegenerateFail { conversation_id } => write!(
+                f,
+                "The latest message of conversation {} is not an assistant reply",
+                conversation_id
+            ),
+            MyError::NotAUserMessageFail { message_id } => {
+                write!(f, "Message {} is not a user message, only those can be edited", message_id)
+            }
+            MyError::EmptyMessageFail { message_id } => {
+                write!(f, "Message {} can't be edited to be empty", message_id)
+            }
+            MyError::UsageReadFromDiskFail { message } => {
+                write!(f, "Failed to read usage from disk: {}", message)
+            }
+            MyError::ConversationReadFromDiskFail {
+                conversation_id,
+                message,
+            } => write!(
+                f,
+                "Failed to read conversation {} from disk: {}",
+                conversation_id, message
+            ),
+            MyError::PageCursorNotFoundFail { cursor } => {
+                write!(f, "Page cursor {} is not in the conversation", cursor)
+            }
+            MyError::SearchFail { message } => {
+                write!(f, "Failed to search conversations: {}", message)
+            }
+            MyError::EmbeddingFail(e) => write!(f, "Failed to compute embeddings: {}", e),
+            MyError::ContextWindowExceededFail { tokens, available } => write!(
+                f,
+                "The latest message takes about {} tokens, only {} fit in the model's context window",
+                tokens, available
+            ),
+            MyError::SpendingLimitExceededFail { limit, spent, cap } => write!(
+                f,
+                "The {} spending limit of ${:.2} would be exceeded, ${:.2} is already spent",
+                limit, cap, spent
+            ),
+            MyError::UnpricedModelFail { model } => write!(
+                f,
+                "Model {} has no price in model_prices, so the spending limits can't be enforced",
+                model
+            ),
+            MyError::DirListFail { path, message } => {
+                write!(f, "Failed to list directory {}: {}", path, message)
             }
-            MyError::ConversationEmptyFail => write!(f, "Conversation is empty"),
-            MyError::ConversationAIResponseFail => write!(f, "Failed to get AI response"),
-            MyError::DirListFail => write!(f, "Failed to list directory"),
         }
     }
 }
 impl std::error::Error for MyError {}
 
-#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize, Eq, Ord, Type)]
-pub enum ChatRole {
-    System,
-    Assistant,
-    User
+/// Why a request to the provider failed.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Type)]
+pub struct ProviderError {
+    /// Status the server responded with, `None` when the failure isn't an error response.
+    pub status: Option<u16>,
+    pub message: String,
+    /// Whether the same request may succeed later, e.g. after rate limiting or a timeout.
+    pub retryable: bool,
 }
-
-impl From<chatgpt::types::Role> for ChatRole {
-    fn from(role: chatgpt::types::Role) -> Self {
-        match role {
-            chatgpt::types::Role::System => ChatRole::System,
-            chatgpt::types::Role::Assistant => ChatRole::Assistant,
-            chatgpt::types::Role::User => ChatRole::User,
+impl ProviderError {
+    /// A failure that trying again won't fix, like a response that can't be parsed.
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            status: None,
+            message: message.into(),
+            retryable: false,
+        }
+    }
+}
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "{} (HTTP {})", self.message, status),
+            None => write!(f, "{}", self.message),
         }
     }
 }
 
-impl From<ChatRole> for chatgpt::types::Role {
-    fn from(chat_role: ChatRole) -> Self {
-        match chat_role {
-            ChatRole::System => chatgpt::types::Role::System,
-            ChatRole::Assistant => chatgpt::types::Role::Assistant,
-            ChatRole::User => chatgpt::types::Role::User,
+pub fn parse_uuid(value: &str) -> Result<Uuid, MyError> {
+    Uuid::parse_str(value).map_err(|_| MyError::UUIDParseFail {
+        value: value.to_string(),
+    })
+}
+
+/// A spending cap from the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub enum SpendingLimit {
+    /// Everything spent since the start of the calendar month, in UTC.
+    Monthly,
+    /// Everything spent on a single conversation.
+    Conversation,
+}
+impl fmt::Display for SpendingLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpendingLimit::Monthly => write!(f, "monthly"),
+            SpendingLimit::Conversation => write!(f, "conversation"),
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize, Eq, Ord, Type)]
+pub enum ChatRole {
+    System,
+    Assistant,
+    User
+}
+
 
 #[derive(Debug, Serialize, Deserialize, Clone, Type)]
 pub struct ConversationMessageAddedEvent {
     pub author: ChatRole,
     pub content: String,
+    /// Set on assistant replies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<CompletionUsage>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Type)]
 pub struct ConversationTitleChangedEvent {
     pub new_title: String,
+    /// Set when the title was generated from the first exchange rather than typed in.
+    #[serde(default)]
+    pub auto_generated: bool,
+    /// Set when the title was generated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<CompletionUsage>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationMessageCancelledEvent {
+    pub author: ChatRole,
+    pub partial_content: String,
+    /// Estimated, the request is dropped before the server reports it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<CompletionUsage>,
+}
+
+/// Model and sampling parameters, `None` leaves the choice to the provider.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Type)]
+pub struct ConversationSettings {
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+}
+impl ConversationSettings {
+    /// Checks the parameters against the ranges accepted by the OpenAI API.
+    pub fn validate(&self) -> Result<(), MyError> {
+        let in_range = |value: Option<f32>, min: f32, max: f32| {
+            value.map_or(true, |value| (min..=max).contains(&value))
+        };
+        let checks = [
+            ("temperature", in_range(self.temperature, 0.0, 2.0)),
+            ("top_p", in_range(self.top_p, 0.0, 1.0)),
+            (
+                "presence_penalty",
+                in_range(self.presence_penalty, -2.0, 2.0),
+            ),
+            (
+                "frequency_penalty",
+                in_range(self.frequency_penalty, -2.0, 2.0),
+            ),
+            ("max_tokens", self.max_tokens != Some(0)),
+            (
+                "model",
+                self.model
+                    .as_ref()
+                    .map_or(true, |model| !model.trim().is_empty()),
+            ),
+        ];
+        match checks.into_iter().find(|(_, valid)| !valid) {
+            Some((setting, _)) => Err(MyError::InvalidSettingsFail {
+                setting: setting.to_string(),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Holds the complete settings in effect from this point of the conversation onwards.
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationSettingsChangedEvent {
+    pub settings: ConversationSettings,
+}
+
+/// Replaces the system prompt for the whole conversation, an empty prompt removes it.
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationSystemPromptChangedEvent {
+    pub content: String,
+}
+
+/// Another variant of an earlier message, it becomes the selected variant when added.
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationMessageAlternativeAddedEvent {
+    pub message_id: Uuid,
+    pub content: String,
+    /// Set when the regeneration was cancelled, the content is what was received until then.
+    #[serde(default)]
+    pub cancelled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<CompletionUsage>,
+}
+
+/// Picks which variant of a message is shown and sent to the model.
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationMessageAlternativeSelectedEvent {
+    pub message_id: Uuid,
+    pub alternative_id: Uuid,
+}
+
+/// A rewrite of an earlier user message, later messages continue from it on a new branch.
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationMessageEditedEvent {
+    pub message_id: Uuid,
+    pub content: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub enum ConversationStatus {
+    Active,
+    Archived,
+    /// Deleted, but kept until the trash retention period has passed.
+    Trashed,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationStatusChangedEvent {
+    pub status: ConversationStatus,
+}
+
+/// Summary of the turns up to a message, sent in their place once they no longer fit in the context window.
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationContextSummarizedEvent {
+    pub summary: String,
+    /// Variant id of the latest message covered, the summary only applies to branches going through it.
+    pub through_alternative_id: Uuid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<CompletionUsage>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Type)]
 pub enum ConversationEvent {
     MessageAdded(ConversationMessageAddedEvent),
     TitleChange(ConversationTitleChangedEvent),
+    MessageCancelled(ConversationMessageCancelledEvent),
+    SettingsChanged(ConversationSettingsChangedEvent),
+    SystemPromptChanged(ConversationSystemPromptChangedEvent),
+    MessageAlternativeAdded(ConversationMessageAlternativeAddedEvent),
+    MessageAlternativeSelected(ConversationMessageAlternativeSelectedEvent),
+    MessageEdited(ConversationMessageEditedEvent),
+    StatusChanged(ConversationStatusChangedEvent),
+    ContextSummarized(ConversationContextSummarizedEvent),
+}
+impl ConversationEvent {
+    /// The text search looks through, message variants and titles have some.
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            ConversationEvent::MessageAdded(event) => Some(&event.content),
+            ConversationEvent::MessageAlternativeAdded(event) => Some(&event.content),
+            ConversationEvent::MessageEdited(event) => Some(&event.content),
+            ConversationEvent::TitleChange(event) => Some(&event.new_title),
+            _ => None,
+        }
+    }
+    /// What the completion the event holds cost, if it holds one.
+    pub fn usage(&self) -> Option<&CompletionUsage> {
+        match self {
+            ConversationEvent::MessageAdded(event) => event.usage.as_ref(),
+            ConversationEvent::MessageCancelled(event) => event.usage.as_ref(),
+            ConversationEvent::MessageAlternativeAdded(event) => event.usage.as_ref(),
+            ConversationEvent::ContextSummarized(event) => event.usage.as_ref(),
+            ConversationEvent::TitleChange(event) => event.usage.as_ref(),
+            _ => None,
+        }
+    }
 }
 impl From<ConversationMessageAddedEvent> for ConversationEvent {
     fn from(event: ConversationMessageAddedEvent) -> Self {
@@ -99,10 +454,58 @@ impl From<ConversationTitleChangedEvent> for ConversationEvent {
     }
 }
 
+impl From<ConversationMessageCancelledEvent> for ConversationEvent {
+    fn from(event: ConversationMessageCancelledEvent) -> Self {
+        ConversationEvent::MessageCancelled(event)
+    }
+}
+
+impl From<ConversationSettingsChangedEvent> for ConversationEvent {
+    fn from(event: ConversationSettingsChangedEvent) -> Self {
+        ConversationEvent::SettingsChanged(event)
+    }
+}
+
+impl From<ConversationSystemPromptChangedEvent> for ConversationEvent {
+    fn from(event: ConversationSystemPromptChangedEvent) -> Self {
+        ConversationEvent::SystemPromptChanged(event)
+    }
+}
 
+impl From<ConversationMessageAlternativeAddedEvent> for ConversationEvent {
+    fn from(event: ConversationMessageAlternativeAddedEvent) -> Self {
+        ConversationEvent::MessageAlternativeAdded(event)
+    }
+}
 
+impl From<ConversationMessageAlternativeSelectedEvent> for ConversationEvent {
+    fn from(event: ConversationMessageAlternativeSelectedEvent) -> Self {
+        ConversationEvent::MessageAlternativeSelected(event)
+    }
+}
+
+impl From<ConversationMessageEditedEvent> for ConversationEvent {
+    fn from(event: ConversationMessageEditedEvent) -> Self {
+        ConversationEvent::MessageEdited(event)
+    }
+}
 
-fn serialize_timestamp<S>(timestamp: &i64, serializer: S) -> Result<S::Ok, S::Error>
+impl From<ConversationStatusChangedEvent> for ConversationEvent {
+    fn from(event: ConversationStatusChangedEvent) -> Self {
+        ConversationEvent::StatusChanged(event)
+    }
+}
+
+impl From<ConversationContextSummarizedEvent> for ConversationEvent {
+    fn from(event: ConversationContextSummarizedEvent) -> Self {
+        ConversationEvent::ContextSummarized(event)
+    }
+}
+
+
+
+
+pub fn serialize_timestamp<S>(timestamp: &i64, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
@@ -110,13 +513,35 @@ where
     serializer.serialize_str(&timestamp_str)
 }
 
-fn deserialize_timestamp<'de, D>(deserializer: D) -> Result<i64, D::Error>
+pub fn deserialize_timestamp<'de, D>(deserializer: D) -> Result<i64, D::Error>
 where
     D: Deserializer<'de>,
 {
     let s = String::deserialize(deserializer)?;
     s.parse::<i64>().map_err(Error::custom)
 }
+
+pub fn serialize_optional_timestamp<S>(
+    timestamp: &Option<i64>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match timestamp {
+        Some(timestamp) => serialize_timestamp(timestamp, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub fn deserialize_optional_timestamp<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| s.parse::<i64>().map_err(Error::custom))
+        .transpose()
+}
 #[derive(Debug, Serialize, Deserialize, Clone, Type)]
 pub struct ConversationEventRecord {
     pub id: uuid::Uuid,
@@ -126,13 +551,234 @@ pub struct ConversationEventRecord {
     pub event: ConversationEvent,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationMessageAlternative {
+    pub id: Uuid,
+    pub content: String,
+}
+
+/// A message on the active branch, with every variant recorded for it.
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationMessage {
+    /// Id of the record that first added the message.
+    pub id: Uuid,
+    pub author: ChatRole,
+    pub content: String,
+    pub selected_alternative_id: Uuid,
+    /// Every variant in the order they were made, starting with the original message.
+    pub alternatives: Vec<ConversationMessageAlternative>,
+}
+
+/// A slice of a conversation's history, `has_more_*` tell whether there is more to page through.
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationHistoryPage {
+    pub records: Vec<ConversationEventRecord>,
+    pub has_more_before: bool,
+    pub has_more_after: bool,
+}
+
+/// A slice of the messages on the active branch.
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationMessagesPage {
+    pub messages: Vec<ConversationMessage>,
+    pub has_more_before: bool,
+    pub has_more_after: bool,
+}
+
+/// Range of up to `limit` items strictly between the `after` and `before` cursors.
+///
+/// It starts right after `after` when that is given, otherwise it ends right before `before`,
+/// so without cursors it holds the latest items.
+fn page_range<T>(
+    items: &[T],
+    id_of: impl Fn(&T) -> Uuid,
+    before: Option<Uuid>,
+    after: Option<Uuid>,
+    limit: usize,
+) -> Result<Range<usize>, MyError> {
+    let position = |cursor: Uuid| {
+        items
+            .iter()
+            .position(|item| id_of(item) == cursor)
+            .ok_or(MyError::PageCursorNotFoundFail { cursor })
+    };
+    let start = match after {
+        Some(cursor) => position(cursor)? + 1,
+        None => 0,
+    };
+    let end = match before {
+        Some(cursor) => position(cursor)?.max(start),
+        None => items.len(),
+    };
+    if after.is_some() {
+        Ok(start..end.min(start + limit))
+    } else {
+        Ok(end.saturating_sub(limit).max(start)..end)
+    }
+}
+
+/// One variant of a message, placed under the variant it followed.
+struct MessageNode {
+    message_id: Uuid,
+    parent_id: Option<Uuid>,
+    author: ChatRole,
+    content: String,
+}
+
+/// Every message variant in the history, keyed by the id of the record that made it.
+///
+/// Variants of the same message are siblings, so editing or regenerating one starts a new branch
+/// while the messages that followed the others stay reachable by selecting them again.
+struct MessageTree {
+    nodes: HashMap<Uuid, MessageNode>,
+    /// Variant ids of each message in the order they were recorded, keyed by message id.
+    variants: HashMap<Uuid, Vec<Uuid>>,
+    /// The variant followed under each parent, `None` being the start of the conversation.
+    selected_children: HashMap<Option<Uuid>, Uuid>,
+    /// Last variant of the active branch, which new messages follow.
+    tail: Option<Uuid>,
+}
+impl MessageTree {
+    fn from_history(history: &[ConversationEventRecord]) -> Self {
+        let mut tree = Self {
+            nodes: HashMap::new(),
+            variants: HashMap::new(),
+            selected_children: HashMap::new(),
+            tail: None,
+        };
+        for record in history {
+            match &record.event {
+                ConversationEvent::MessageAdded(msg) => {
+                    let parent_id = tree.tail;
+                    tree.insert(record.id, record.id, parent_id, msg.author, &msg.content);
+                }
+                ConversationEvent::MessageAlternativeAdded(
+                    ConversationMessageAlternativeAddedEvent {
+                        message_id,
+                        content,
+                        ..
+                    },
+                )
+                | ConversationEvent::MessageEdited(ConversationMessageEditedEvent {
+                    message_id,
+                    content,
+                }) => {
+                    if let Some(original) = tree.nodes.get(message_id) {
+                        let (parent_id, author) = (original.parent_id, original.author);
+                        tree.insert(record.id, *message_id, parent_id, author, content);
+                    }
+                }
+                ConversationEvent::MessageAlternativeSelected(event) => {
+                    if let Some(node) = tree
+                        .nodes
+                        .get(&event.alternative_id)
+                        .filter(|node| node.message_id == event.message_id)
+                    {
+                        tree.select(node.parent_id, event.alternative_id);
+                    }
+                }
+                _ => {}
+            }
+        }
+        tree
+    }
+    fn insert(
+        &mut self,
+        id: Uuid,
+        message_id: Uuid,
+        parent_id: Option<Uuid>,
+        author: ChatRole,
+        content: &str,
+    ) {
+        self.nodes.insert(
+            id,
+            MessageNode {
+                message_id,
+                parent_id,
+                author,
+                content: content.to_string(),
+            },
+        );
+        self.variants.entry(message_id).or_default().push(id);
+        self.select(parent_id, id);
+    }
+    /// Follows `id` under `parent_id` from now on.
+    ///
+    /// Following a child of the tail only extends the branch, anything else may switch to
+    /// another branch so its end is looked up again.
+    fn select(&mut self, parent_id: Option<Uuid>, id: Uuid) {
+        self.selected_children.insert(parent_id, id);
+        self.tail = if parent_id == self.tail {
+            self.branch_end(Some(id))
+        } else {
+            self.branch_end(None)
+        };
+    }
+    /// Last variant reached by following the selected children from `parent_id`.
+    fn branch_end(&self, mut parent_id: Option<Uuid>) -> Option<Uuid> {
+        while let Some(id) = self.selected_children.get(&parent_id) {
+            parent_id = Some(*id);
+        }
+        parent_id
+    }
+    /// The message with variant `id` selected.
+    fn message(&self, id: Uuid) -> ConversationMessage {
+        let node = &self.nodes[&id];
+        let alternatives = self.variants[&node.message_id]
+            .iter()
+            .map(|alt_id| ConversationMessageAlternative {
+                id: *alt_id,
+                content: self.nodes[alt_id].content.clone(),
+            })
+            .collect();
+        ConversationMessage {
+            id: node.message_id,
+            author: node.author,
+            content: node.content.clone(),
+            selected_alternative_id: id,
+            alternatives,
+        }
+    }
+    /// Variant ids from the first message to the latest, following the selected child at each step.
+    fn active_branch(&self) -> Vec<Uuid> {
+        let mut branch = Vec::new();
+        let mut parent_id = None;
+        while let Some(id) = self.selected_children.get(&parent_id) {
+            branch.push(*id);
+            parent_id = Some(*id);
+        }
+        branch
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Type)]
 pub struct Conversation {
     pub id: uuid::Uuid,
     pub history: Vec<ConversationEventRecord>,
 }
 
-const DEFAULT_CONVERSATION_TITLE: &str = "Untitled Conversation";
+pub const DEFAULT_CONVERSATION_TITLE: &str = "Untitled Conversation";
+const TITLE_PROMPT: &str = "Write a title of at most six words for the conversation below. \
+    Reply with the title only, without quotes.";
+/// Characters of each message shown to the model when asking for a title.
+const TITLE_CONTEXT_CHARS: usize = 1000;
+const MAX_TITLE_CHARS: usize = 80;
+pub const MAX_TITLE_TOKENS: u32 = 20;
+
+/// Cleans up a title written by the model, `None` if nothing usable is left.
+pub fn parse_generated_title(content: &str) -> Option<String> {
+    let line = content.lines().map(str::trim).find(|line| !line.is_empty())?;
+    let line = line.strip_prefix("Title:").unwrap_or(line);
+    let title: String = line
+        .trim()
+        .trim_matches(|c| c == '"' || c == '\'' || c == '*')
+        .trim_end_matches('.')
+        .trim()
+        .chars()
+        .take(MAX_TITLE_CHARS)
+        .collect();
+    (!title.is_empty()).then_some(title)
+}
 impl Conversation {
     pub fn new() -> Self {
         let conv = Self {
@@ -148,12 +794,29 @@ impl Conversation {
             .filter(|record| match record.event {
                 ConversationEvent::TitleChange(_) => TypeId::of::<T>() == TypeId::of::<ConversationTitleChangedEvent>(),
                 ConversationEvent::MessageAdded(_) => TypeId::of::<T>() == TypeId::of::<ConversationMessageAddedEvent>(),
+                ConversationEvent::MessageCancelled(_) => TypeId::of::<T>() == TypeId::of::<ConversationMessageCancelledEvent>(),
+                ConversationEvent::SettingsChanged(_) => TypeId::of::<T>() == TypeId::of::<ConversationSettingsChangedEvent>(),
+                ConversationEvent::SystemPromptChanged(_) => TypeId::of::<T>() == TypeId::of::<ConversationSystemPromptChangedEvent>(),
+                ConversationEvent::MessageAlternativeAdded(_) => TypeId::of::<T>() == TypeId::of::<ConversationMessageAlternativeAddedEvent>(),
+                ConversationEvent::MessageAlternativeSelected(_) => TypeId::of::<T>() == TypeId::of::<ConversationMessageAlternativeSelectedEvent>(),
+                ConversationEvent::MessageEdited(_) => TypeId::of::<T>() == TypeId::of::<ConversationMessageEditedEvent>(),
+                ConversationEvent::StatusChanged(_) => TypeId::of::<T>() == TypeId::of::<ConversationStatusChangedEvent>(),
+                ConversationEvent::ContextSummarized(_) => {
+                    TypeId::of::<T>() == TypeId::of::<ConversationContextSummarizedEvent>()
+                }
             })
             .max_by_key(|record| record.timestamp)
     }
     pub fn add_event<E: Into<ConversationEvent>>(&mut self, event: E) -> &ConversationEventRecord {
+        self.add_event_with_id(uuid::Uuid::new_v4(), event)
+    }
+    pub fn add_event_with_id<E: Into<ConversationEvent>>(
+        &mut self,
+        id: Uuid,
+        event: E,
+    ) -> &ConversationEventRecord {
         let record = ConversationEventRecord {
-            id: uuid::Uuid::new_v4(),
+            id,
             conversation_id: self.id,
             timestamp: chrono::Utc::now().timestamp_millis(),
             event: event.into(),
@@ -161,22 +824,85 @@ impl Conversation {
         self.history.push(record);
         self.history.last().unwrap()
     }
-    pub fn into_chatgpt_conversation(&self, chatgpt: ChatGPT) -> chatgpt::converse::Conversation {
-        let history: Vec<chatgpt::types::ChatMessage> = self
+    /// Builds the history sent to the model from `branch`, led by the latest system prompt wherever it was set.
+    ///
+    /// Messages covered by the latest summary recorded on the branch are sent as that summary.
+    pub fn build_context(
+        &self,
+        branch: &[ConversationMessage],
+        budget: u32,
+    ) -> Result<ChatContext, MyError> {
+        let summary = self
             .history
             .iter()
-            .filter_map(|record| {
-                if let ConversationEvent::MessageAdded(msg) = &record.event {
-                    Some(ChatMessage {
-                        content: msg.content.clone(),
-                        role: msg.author.into(),
-                    })
+            .rev()
+            .find_map(|record| match &record.event {
+                ConversationEvent::ContextSummarized(event) => branch
+                    .iter()
+                    .position(|msg| msg.selected_alternative_id == event.through_alternative_id)
+                    .map(|position| (event.summary.as_str(), position + 1)),
+                _ => None,
+            });
+        let covered = summary.map_or(0, |(_, covered)| covered);
+        crate::context::build_context(
+            self.get_system_prompt().map(String::as_str),
+            summary.map(|(summary, _)| summary),
+            &branch[covered..],
+            budget,
+        )
+    }
+    /// Lists the messages on the active branch of the message tree.
+    pub fn get_messages(&self) -> Vec<ConversationMessage> {
+        let tree = MessageTree::from_history(&self.history);
+        tree.active_branch()
+            .into_iter()
+            .map(|id| tree.message(id))
+            .collect()
+    }
+    pub fn get_history_page(
+        &self,
+        before: Option<Uuid>,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<ConversationHistoryPage, MyError> {
+        let range = page_range(&self.history, |record| record.id, before, after, limit)?;
+        Ok(ConversationHistoryPage {
+            has_more_before: range.start > 0,
+            has_more_after: range.end < self.history.len(),
+            records: self.history[range].to_vec(),
+        })
+    }
+    /// Pages through the active branch, the cursors are message ids.
+    pub fn get_messages_page(
+        &self,
+        before: Option<Uuid>,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Result<ConversationMessagesPage, MyError> {
+        let tree = MessageTree::from_history(&self.history);
+        let branch = tree.active_branch();
+        let range = page_range(
+            &branch,
+            |id| tree.nodes[id].message_id,
+            before,
+            after,
+            limit,
+        )?;
+        Ok(ConversationMessagesPage {
+            has_more_before: range.start > 0,
+            has_more_after: range.end < branch.len(),
+            messages: branch[range].iter().map(|id| tree.message(*id)).collect(),
+        })
+    }
+    pub fn get_system_prompt(&self) -> Option<&String> {
+        self.get_latest_event::<ConversationSystemPromptChangedEvent>()
+            .and_then(|record| {
+                if let ConversationEvent::SystemPromptChanged(event) = &record.event {
+                    Some(&event.content)
                 } else {
                     None
                 }
             })
-            .collect();
-        chatgpt::converse::Conversation::new_with_history(chatgpt, history)
     }
     pub fn get_title(&self) -> Cow<'_, String> {
         self.get_latest_event::<ConversationTitleChangedEvent>()
@@ -189,11 +915,93 @@ impl Conversation {
             })
             .unwrap_or_else(|| Cow::Owned(DEFAULT_CONVERSATION_TITLE.to_string()))
     }
+    /// Messages asking for a title, once the first reply is in and as long as no title was ever set.
+    pub fn title_request(&self) -> Option<Vec<ChatMessage>> {
+        if self
+            .get_latest_event::<ConversationTitleChangedEvent>()
+            .is_some()
+        {
+            return None;
+        }
+        let messages = self.get_messages();
+        let reply = messages
+            .iter()
+            .position(|message| message.author == ChatRole::Assistant)?;
+        let exchange: String = messages[..=reply]
+            .iter()
+            .map(|message| {
+                let content: String = message.content.chars().take(TITLE_CONTEXT_CHARS).collect();
+                format!("{:?}: {}\n", message.author, content)
+            })
+            .collect();
+        Some(vec![
+            ChatMessage {
+                role: ChatRole::System,
+                content: TITLE_PROMPT.to_string(),
+            },
+            ChatMessage {
+                role: ChatRole::User,
+                content: exchange,
+            },
+        ])
+    }
+    pub fn get_settings(&self) -> Cow<'_, ConversationSettings> {
+        self.get_latest_event::<ConversationSettingsChangedEvent>()
+            .and_then(|record| {
+                if let ConversationEvent::SettingsChanged(event) = &record.event {
+                    Some(Cow::Borrowed(&event.settings))
+                } else {
+                    None
+                }
+            })
+            .unwrap_or_default()
+    }
+    pub fn get_status(&self) -> ConversationStatus {
+        self.get_latest_event::<ConversationStatusChangedEvent>()
+            .and_then(|record| {
+                if let ConversationEvent::StatusChanged(event) = &record.event {
+                    Some(event.status)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(ConversationStatus::Active)
+    }
+    /// When the conversation was moved to the trash, if it is still there.
+    pub fn get_trashed_at(&self) -> Option<i64> {
+        self.get_latest_event::<ConversationStatusChangedEvent>()
+            .filter(|record| {
+                matches!(
+                    &record.event,
+                    ConversationEvent::StatusChanged(ConversationStatusChangedEvent {
+                        status: ConversationStatus::Trashed
+                    })
+                )
+            })
+            .map(|record| record.timestamp)
+    }
+    pub fn summarize(&self) -> ConversationSummary {
+        ConversationSummary {
+            id: self.id,
+            title: self.get_title().into_owned(),
+            status: self.get_status(),
+            updated_at: self.history.last().map_or(0, |record| record.timestamp),
+            message_count: self.get_messages().len() as u32,
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::storage::SqliteStore;
+
+    /// Everything sent to the model when nothing has to be left out.
+    fn chat_messages(conv: &Conversation) -> Vec<ChatMessage> {
+        conv.build_context(&conv.get_messages(), u32::MAX)
+            .unwrap()
+            .messages
+    }
 
     #[test]
     fn test_get_title() {
@@ -204,33 +1012,766 @@ mod test {
         );
         let latest = conv.add_event(ConversationTitleChangedEvent {
             new_title: "New Title".to_string(),
+            auto_generated: false,
+            usage: None,
         }).id;
         assert_eq!(conv.get_latest_event::<ConversationTitleChangedEvent>().unwrap().id, latest);
         assert_eq!(conv.get_title().as_ref(), "New Title");
         conv.add_event(ConversationTitleChangedEvent {
             new_title: "Newer Title".to_string(),
+            auto_generated: false,
+            usage: None,
         });
         assert_eq!(conv.get_title().as_ref(), "Newer Title");
     }
+
+    #[test]
+    fn test_title_request() {
+        let mut conv = Conversation::new();
+        conv.add_event(ConversationMessageAddedEvent {
+            author: ChatRole::User,
+            content: "How do I sort a Vec?".to_string(),
+            usage: None,
+        });
+        assert!(conv.title_request().is_none());
+        conv.add_event(ConversationMessageAddedEvent {
+            author: ChatRole::Assistant,
+            content: "Call sort on it.".to_string(),
+            usage: None,
+        });
+        let request = conv.title_request().unwrap();
+        assert_eq!(
+            request[1].content,
+            "User: How do I sort a Vec?\nAssistant: Call sort on it.\n"
+        );
+
+        // Any title, generated or not, is never replaced
+        conv.add_event(ConversationTitleChangedEvent {
+            new_title: "Sorting".to_string(),
+            auto_generated: false,
+            usage: None,
+        });
+        assert!(conv.title_request().is_none());
+
+        assert_eq!(
+            parse_generated_title("\n\"Sorting a Vec.\"\nmore").as_deref(),
+            Some("Sorting a Vec")
+        );
+        assert_eq!(parse_generated_title("Title: Rust\n").as_deref(), Some("Rust"));
+        assert!(parse_generated_title("  \"\" ").is_none());
+    }
+
+    #[test]
+    fn test_get_settings() {
+        let mut conv = Conversation::new();
+        assert_eq!(conv.get_settings().as_ref(), &ConversationSettings::default());
+        let settings = ConversationSettings {
+            model: Some("gpt-4".to_string()),
+            temperature: Some(0.2),
+            max_tokens: Some(256),
+            ..Default::default()
+        };
+        conv.add_event(ConversationSettingsChangedEvent {
+            settings: settings.clone(),
+        });
+        assert_eq!(conv.get_settings().as_ref(), &settings);
+    }
+
+    #[test]
+    fn test_system_prompt_leads_chat_messages() {
+        let mut conv = Conversation::new();
+        conv.add_event(ConversationSystemPromptChangedEvent {
+            content: "Be brief.".to_string(),
+        });
+        conv.add_event(ConversationMessageAddedEvent {
+            author: ChatRole::User,
+            content: "Hello".to_string(),
+            usage: None,
+        });
+        conv.add_event(ConversationSystemPromptChangedEvent {
+            content: "Be verbose.".to_string(),
+        });
+        let messages = chat_messages(&conv);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].role, ChatRole::System);
+        assert_eq!(messages[0].content, "Be verbose.");
+        assert_eq!(messages[1].content, "Hello");
+
+        conv.add_event(ConversationSystemPromptChangedEvent {
+            content: String::new(),
+        });
+        assert_eq!(chat_messages(&conv).len(), 1);
+    }
+
+    #[test]
+    fn test_message_alternatives() {
+        let mut conv = Conversation::new();
+        conv.add_event(ConversationMessageAddedEvent {
+            author: ChatRole::User,
+            content: "Tell me a joke".to_string(),
+            usage: None,
+        });
+        let original = conv
+            .add_event(ConversationMessageAddedEvent {
+                author: ChatRole::Assistant,
+                content: "First joke".to_string(),
+                usage: None,
+            })
+            .id;
+        let regenerated = conv
+            .add_event(ConversationMessageAlternativeAddedEvent {
+                message_id: original,
+                content: "Second joke".to_string(),
+                cancelled: false,
+                usage: None,
+            })
+            .id;
+
+        let messages = conv.get_messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].alternatives.len(), 2);
+        assert_eq!(messages[1].selected_alternative_id, regenerated);
+        assert_eq!(chat_messages(&conv)[1].content, "Second joke");
+
+        conv.add_event(ConversationMessageAlternativeSelectedEvent {
+            message_id: original,
+            alternative_id: original,
+        });
+        assert_eq!(chat_messages(&conv)[1].content, "First joke");
+
+        // A cancelled regeneration is kept among the variants too
+        conv.add_event(ConversationMessageAlternativeAddedEvent {
+            message_id: original,
+            content: "Third".to_string(),
+            cancelled: true,
+            usage: None,
+        });
+        assert_eq!(conv.get_messages()[1].alternatives.len(), 3);
+        assert_eq!(chat_messages(&conv)[1].content, "Third");
+    }
+
+    #[test]
+    fn test_edit_forks_conversation() {
+        let mut conv = Conversation::new();
+        let add = |conv: &mut Conversation, author, content: &str| {
+            conv.add_event(ConversationMessageAddedEvent {
+                author,
+                content: content.to_string(),
+                usage: None,
+            })
+            .id
+        };
+        add(&mut conv, ChatRole::User, "Hi");
+        add(&mut conv, ChatRole::Assistant, "Hello");
+        let original = add(&mut conv, ChatRole::User, "Tell me a joke");
+        add(&mut conv, ChatRole::Assistant, "A joke");
+
+        conv.add_event(ConversationMessageEditedEvent {
+            message_id: original,
+            content: "Tell me a story".to_string(),
+        });
+        let messages = conv.get_messages();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[2].alternatives.len(), 2);
+        assert_eq!(messages[2].content, "Tell me a story");
+
+        add(&mut conv, ChatRole::Assistant, "A story");
+        assert_eq!(chat_messages(&conv)[3].content, "A story");
+
+        // The original branch is still there, with the reply it had
+        conv.add_event(ConversationMessageAlternativeSelectedEvent {
+            message_id: original,
+            alternative_id: original,
+        });
+        let messages = chat_messages(&conv);
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[2].content, "Tell me a joke");
+        assert_eq!(messages[3].content, "A joke");
+
+        // New messages follow the selected branch
+        add(&mut conv, ChatRole::User, "Another one");
+        let messages = chat_messages(&conv);
+        assert_eq!(messages.len(), 5);
+        assert_eq!(messages[3].content, "A joke");
+    }
+
+    #[test]
+    fn test_pagination() {
+        let mut conv = Conversation::new();
+        let ids: Vec<Uuid> = (0..5)
+            .map(|i| {
+                conv.add_event(ConversationMessageAddedEvent {
+                    author: ChatRole::User,
+                    content: i.to_string(),
+                    usage: None,
+                })
+                .id
+            })
+            .collect();
+
+        let latest = conv.get_history_page(None, None, 2).unwrap();
+        let latest_ids: Vec<Uuid> = latest.records.iter().map(|record| record.id).collect();
+        assert_eq!(latest_ids, ids[3..]);
+        assert!(latest.has_more_before && !latest.has_more_after);
+
+        let older = conv.get_messages_page(Some(ids[3]), None, 2).unwrap();
+        assert_eq!(older.messages[0].content, "1");
+        assert_eq!(older.messages[1].content, "2");
+        assert!(older.has_more_before && older.has_more_after);
+
+        let newer = conv.get_messages_page(None, Some(ids[0]), 10).unwrap();
+        assert_eq!(newer.messages.len(), 4);
+        assert!(newer.has_more_before && !newer.has_more_after);
+
+        let between = conv.get_history_page(Some(ids[4]), Some(ids[1]), 10).unwrap();
+        assert_eq!(between.records.len(), 2);
+
+        assert!(conv.get_history_page(Some(Uuid::new_v4()), None, 2).is_err());
+    }
+
+    #[test]
+    fn test_purge_trash() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        let mut mgr = ConversationManager::from_disk(Box::new(store), 32).unwrap();
+        let mut kept = Conversation::new();
+        kept.add_event(ConversationStatusChangedEvent {
+            status: ConversationStatus::Trashed,
+        });
+        kept.add_event(ConversationStatusChangedEvent {
+            status: ConversationStatus::Active,
+        });
+        let mut trashed = Conversation::new();
+        let trashed_at = trashed
+            .add_event(ConversationStatusChangedEvent {
+                status: ConversationStatus::Trashed,
+            })
+            .timestamp;
+        assert_eq!(trashed.get_status(), ConversationStatus::Trashed);
+        let (kept_id, trashed_id) = (kept.id, trashed.id);
+        mgr.insert(kept);
+        mgr.insert(trashed);
+
+        assert!(mgr.purge_trash(trashed_at + 10, 1000).unwrap().is_empty());
+        assert_eq!(
+            mgr.purge_trash(trashed_at + 1000, 1000).unwrap(),
+            vec![trashed_id]
+        );
+        assert!(mgr.get(&kept_id).is_ok());
+        assert!(mgr.get(&trashed_id).is_err());
+    }
+
+    #[test]
+    fn test_lazy_loading() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        let mut mgr = ConversationManager::from_disk(Box::new(store), 1).unwrap();
+        let loaded_count = |mgr: &ConversationManager| mgr.cache.lock().unwrap().loaded.len();
+        let mut first = Conversation::new();
+        first.add_event(ConversationTitleChangedEvent {
+            new_title: "First".to_string(),
+            auto_generated: false,
+            usage: None,
+        });
+        let (first_id, second_id) = (first.id, Conversation::new().id);
+        mgr.insert(first);
+        mgr.insert(Conversation {
+            id: second_id,
+            history: Vec::new(),
+        });
+        // Unsaved conversations are never unloaded
+        assert_eq!(loaded_count(&mgr), 2);
+        mgr.write_to_disk().unwrap();
+        assert_eq!(loaded_count(&mgr), 1);
+
+        assert_eq!(mgr.get(&first_id).unwrap().get_title().as_str(), "First");
+        assert_eq!(loaded_count(&mgr), 1);
+
+        mgr.get_mut(&second_id)
+            .unwrap()
+            .add_event(ConversationTitleChangedEvent {
+                new_title: "Second".to_string(),
+                auto_generated: false,
+                usage: None,
+            });
+        mgr.get(&first_id).unwrap();
+        assert_eq!(loaded_count(&mgr), 2);
+        let summaries = mgr.summaries();
+        let second = summaries.iter().find(|summary| summary.id == second_id).unwrap();
+        assert_eq!(second.title, "Second");
+        assert_eq!(second.message_count, 0);
+    }
+
+    #[test]
+    fn test_validate_settings() {
+        assert!(ConversationSettings::default().validate().is_ok());
+        let settings = ConversationSettings {
+            temperature: Some(2.5),
+            ..Default::default()
+        };
+        assert!(settings.validate().is_err());
+        let settings = ConversationSettings {
+            max_tokens: Some(0),
+            ..Default::default()
+        };
+        assert!(settings.validate().is_err());
+    }
+}
+
+/// What the conversation list needs to know about a conversation, kept in memory for all of them.
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationSummary {
+    pub id: Uuid,
+    pub title: String,
+    pub status: ConversationStatus,
+    /// Timestamp of the latest record.
+    #[serde(serialize_with = "serialize_timestamp", deserialize_with = "deserialize_timestamp")]
+    pub updated_at: i64,
+    /// Number of messages on the active branch.
+    pub message_count: u32,
+}
+
+/// A conversation whose history is in memory.
+struct LoadedConversation {
+    conversation: Arc<Conversation>,
+    /// How many of its records the store already has, `None` until it is first saved.
+    saved_length: Option<usize>,
+    /// Value of `ConversationCache::clock` when it was last used.
+    last_used: u64,
+}
+impl LoadedConversation {
+    fn is_saved(&self) -> bool {
+        self.saved_length == Some(self.conversation.history.len())
+    }
+}
+
+struct ConversationCache {
+    index: HashMap<Uuid, ConversationSummary>,
+    loaded: HashMap<Uuid, LoadedConversation>,
+    /// Conversations removed since the last save.
+    removed: Vec<Uuid>,
+    clock: u64,
+}
+impl ConversationCache {
+    /// Loads the conversation from the store if it isn't in memory, and marks it as the most recently used.
+    fn load(
+        &mut self,
+        store: &dyn ConversationStore,
+        id: &Uuid,
+    ) -> Result<&mut LoadedConversation, MyError> {
+        if !self.index.contains_key(id) {
+            return Err(MyError::FindByIDFail { id: *id });
+        }
+        if !self.loaded.contains_key(id) {
+            let conversation = store
+                .load_conversation(id)
+                .map_err(|e| MyError::ConversationReadFromDiskFail {
+                    conversation_id: *id,
+                    message: e.to_string(),
+                })?
+                .ok_or(MyError::FindByIDFail { id: *id })?;
+            self.loaded.insert(
+                *id,
+                LoadedConversation {
+                    saved_length: Some(conversation.history.len()),
+                    conversation: Arc::new(conversation),
+                    last_used: 0,
+                },
+            );
+        }
+        self.clock += 1;
+        let clock = self.clock;
+        let entry = self.loaded.get_mut(id).unwrap();
+        entry.last_used = clock;
+        Ok(entry)
+    }
+    /// Unloads the least recently used conversations past `capacity`.
+    ///
+    /// Conversations with unsaved records stay until they are saved, and so does the one used last.
+    fn evict(&mut self, capacity: usize) {
+        while self.loaded.len() > capacity {
+            let coldest = self
+                .loaded
+                .iter()
+                .filter(|(_, entry)| entry.is_saved() && entry.last_used < self.clock)
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(id, _)| *id);
+            match coldest {
+                Some(id) => {
+                    self.loaded.remove(&id);
+                }
+                None => break,
+            }
+        }
+    }
+    /// Brings the summaries of the conversations changed since the last save up to date.
+    fn refresh_index(&mut self) {
+        for (id, entry) in &self.loaded {
+            if !entry.is_saved() {
+                self.index.insert(*id, entry.conversation.summarize());
+            }
+        }
+    }
 }
 
+/// Keeps a summary of every conversation in memory, and only the histories recently used.
 pub struct ConversationManager {
-    pub conversations: HashMap<Uuid, Conversation>,
+    cache: Mutex<ConversationCache>,
+    store: Box<dyn ConversationStore>,
+    embedding_index: Mutex<EmbeddingIndex>,
+    /// How many histories stay loaded.
+    capacity: usize,
 }
 impl ConversationManager {
+    pub fn from_disk(
+        store: Box<dyn ConversationStore>,
+        capacity: usize,
+    ) -> Result<Self, std::io::Error> {
+        let mut loaded = HashMap::new();
+        let index = if store.loads_lazily() {
+            store.load_index()?
+        } else {
+            let conversations = store.load()?;
+            if conversations.len() > capacity {
+                eprintln!(
+                    "There are {} conversations but max_loaded_conversations is {}, \
+                    the JSON storage keeps them all loaded, switch to SQLite to unload them",
+                    conversations.len(),
+                    capacity
+                );
+            }
+            let mut index = HashMap::new();
+            for (id, conversation) in conversations {
+                index.insert(id, conversation.summarize());
+                loaded.insert(
+                    id,
+                    LoadedConversation {
+                        saved_length: Some(conversation.history.len()),
+                        conversation: Arc::new(conversation),
+                        last_used: 0,
+                    },
+                );
+            }
+            index
+        };
+        let mut embedding_index = EmbeddingIndex::new();
+        for embedding in store.load_embeddings()? {
+            if index.contains_key(&embedding.conversation_id) {
+                embedding_index.insert(embedding);
+            }
+        }
+        // A store that doesn't load lazily couldn't bring back what was unloaded.
+        let capacity = if store.loads_lazily() {
+            capacity
+        } else {
+            usize::MAX
+        };
+        Ok(Self {
+            cache: Mutex::new(ConversationCache {
+                index,
+                loaded,
+                removed: Vec::new(),
+                clock: 0,
+            }),
+            store,
+            embedding_index: Mutex::new(embedding_index),
+            capacity,
+        })
+    }
+    /// Returns the conversation, loading its history if needed.
+    pub fn get(&self, id: &Uuid) -> Result<Arc<Conversation>, MyError> {
+        let mut cache = self.cache.lock().unwrap();
+        let conversation = cache.load(self.store.as_ref(), id)?.conversation.clone();
+        cache.evict(self.capacity);
+        Ok(conversation)
+    }
+    pub fn get_mut(&mut self, id: &Uuid) -> Result<&mut Conversation, MyError> {
+        let cache = self.cache.get_mut().unwrap();
+        cache.load(self.store.as_ref(), id)?;
+        cache.evict(self.capacity);
+        let entry = cache.loaded.get_mut(id).unwrap();
+        // Only copies the history if a reader still holds on to it.
+        Ok(Arc::make_mut(&mut entry.conversation))
+    }
+    pub fn insert(&mut self, conversation: Conversation) {
+        let cache = self.cache.get_mut().unwrap();
+        cache.clock += 1;
+        cache
+            .index
+            .insert(conversation.id, conversation.summarize());
+        cache.loaded.insert(
+            conversation.id,
+            LoadedConversation {
+                conversation: Arc::new(conversation),
+                saved_length: None,
+                last_used: cache.clock,
+            },
+        );
+        cache.evict(self.capacity);
+    }
+    pub fn remove(&mut self, id: &Uuid) {
+        let cache = self.cache.get_mut().unwrap();
+        if cache.index.remove(id).is_some() {
+            cache.loaded.remove(id);
+            cache.removed.push(*id);
+        }
+    }
+    /// Summaries of every conversation, loaded or not.
+    pub fn summaries(&self) -> Vec<ConversationSummary> {
+        let mut cache = self.cache.lock().unwrap();
+        cache.refresh_index();
+        cache.index.values().cloned().collect()
+    }
+    /// Appends an event to a conversation, only needs the lock for the duration of the append.
+    pub fn add_event_with_id<E: Into<ConversationEvent>>(
+        &mut self,
+        conversation_id: &Uuid,
+        id: Uuid,
+        event: E,
+    ) -> Result<&ConversationEventRecord, MyError> {
+        let conv = self.get_mut(conversation_id)?;
+        Ok(conv.add_event_with_id(id, event))
+    }
+    /// Saves the conversations created or removed and the records added since the last save.
+    pub fn write_to_disk(&self) -> Result<(), std::io::Error> {
+        let mut cache = self.cache.lock().unwrap();
+        let mut changes: Vec<ConversationChange> = cache
+            .removed
+            .iter()
+            .map(|id| ConversationChange::ConversationRemoved(*id))
+            .collect();
+        let mut summaries = Vec::new();
+        for (id, entry) in &cache.loaded {
+            if entry.is_saved() {
+                continue;
+            }
+            if entry.saved_length.is_none() {
+                changes.push(ConversationChange::ConversationCreated(*id));
+            }
+            changes.extend(
+                entry.conversation.history[entry.saved_length.unwrap_or(0)..]
+                    .iter()
+                    .cloned()
+                    .map(ConversationChange::EventAdded),
+            );
+            summaries.push(entry.conversation.summarize());
+        }
+        self.store.append(&changes, &summaries)?;
+
+        for change in &changes {
+            if let ConversationChange::ConversationRemoved(id) = change {
+                self.embedding_index.lock().unwrap().remove_conversation(id);
+            }
+        }
+        cache.removed.clear();
+        for summary in summaries {
+            let entry = cache.loaded.get_mut(&summary.id).unwrap();
+            entry.saved_length = Some(entry.conversation.history.len());
+            cache.index.insert(summary.id, summary);
+        }
+        cache.evict(self.capacity);
+        Ok(())
+    }
+    /// Searches the saved messages and titles of every conversation.
+    pub fn search(
+        &self,
+        query: &str,
+        filters: &SearchFilters,
+    ) -> Result<Vec<SearchResult>, std::io::Error> {
+        let cache = self.cache.lock().unwrap();
+        search::search(self.store.as_ref(), &cache.index, query, filters)
+    }
+    /// Saved message variants without an embedding from `model`, as `(event id, conversation id, text)`.
+    pub fn pending_embeddings(
+        &self,
+        model: &str,
+    ) -> Result<Vec<(Uuid, Uuid, String)>, std::io::Error> {
+        let pending: Vec<Uuid> = {
+            let embedding_index = self.embedding_index.lock().unwrap();
+            self.store
+                .message_documents()?
+                .into_iter()
+                .filter(|document| !embedding_index.contains(&document.event_id, model))
+                .map(|document| document.event_id)
+                .collect()
+        };
+        Ok(self
+            .store
+            .load_documents(&pending)?
+            .into_iter()
+            .map(|(document, text)| (document.event_id, document.conversation_id, text))
+            .collect())
+    }
+    pub fn add_embeddings(&self, embeddings: Vec<StoredEmbedding>) -> Result<(), std::io::Error> {
+        self.store.append_embeddings(&embeddings)?;
+        let mut embedding_index = self.embedding_index.lock().unwrap();
+        for embedding in embeddings {
+            embedding_index.insert(embedding);
+        }
+        Ok(())
+    }
+    /// The `top_k` conversations with a message closest to the query vector, leaving out the trash.
+    ///
+    /// Messages are read from the store one at a time, only until there are enough results.
+    pub fn semantic_search(
+        &self,
+        query: &[f32],
+        model: &str,
+        top_k: usize,
+    ) -> Result<Vec<SemanticSearchResult>, std::io::Error> {
+        let cache = self.cache.lock().unwrap();
+        let closest = self
+            .embedding_index
+            .lock()
+            .unwrap()
+            .search(query, model, usize::MAX);
+        let mut results = Vec::new();
+        for (id, score) in closest {
+            if results.len() == top_k {
+                break;
+            }
+            for (document, text) in self.store.load_documents(&[id])? {
+                let Some(summary) = cache
+                    .index
+                    .get(&document.conversation_id)
+                    .filter(|summary| summary.status != ConversationStatus::Trashed)
+                else {
+                    continue;
+                };
+                let result = search::result_for(document, &text, &[], summary.title.clone());
+                results.push(SemanticSearchResult { result, score });
+            }
+        }
+        Ok(results)
+    }
+    /// Saves the usage of requests whose results aren't saved as records.
+    pub fn record_usage(&self, usage: &[UsageRecord]) -> Result<(), std::io::Error> {
+        self.store.append_usage(usage)
+    }
+    /// Dollars spent since `since`, on one conversation or all of them.
+    pub fn spent(
+        &self,
+        prices: &HashMap<String, ModelPrice>,
+        conversation_id: Option<&Uuid>,
+        since: Option<i64>,
+    ) -> Result<f64, MyError> {
+        let period = UsagePeriod {
+            from: since,
+            to: None,
+        };
+        let usage = self
+            .store
+            .load_usage(conversation_id, &period)
+            .map_err(|e| MyError::UsageReadFromDiskFail {
+                message: e.to_string(),
+            })?;
+        Ok(usage::spent(&usage, prices))
+    }
+    /// Aggregates the usage of the saved requests, priced with `prices`.
+    pub fn usage_report(
+        &self,
+        period: &UsagePeriod,
+        prices: &HashMap<String, ModelPrice>,
+    ) -> Result<UsageReport, MyError> {
+        let titles = self
+            .summaries()
+            .into_iter()
+            .map(|summary| (summary.id, summary.title))
+            .collect();
+        let usage =
+            self.store
+                .load_usage(None, period)
+                .map_err(|e| MyError::UsageReadFromDiskFail {
+                    message: e.to_string(),
+                })?;
+        Ok(usage::report(&usage, prices, &titles))
+    }
+    /// Removes the conversations trashed at least `retention_ms` before `now`, returning their ids.
+    pub fn purge_trash(&mut self, now: i64, retention_ms: i64) -> Result<Vec<Uuid>, MyError> {
+        let trashed: Vec<Uuid> = self
+            .summaries()
+            .into_iter()
+            .filter(|summary| summary.status == ConversationStatus::Trashed)
+            .map(|summary| summary.id)
+            .collect();
+        let mut expired = Vec::new();
+        for id in trashed {
+            let trashed_at = self.get(&id)?.get_trashed_at();
+            if trashed_at.map_or(false, |trashed_at| now - trashed_at >= retention_ms) {
+                expired.push(id);
+            }
+        }
+        for id in &expired {
+            self.remove(id);
+        }
+        Ok(expired)
+    }
+}
+
+/// Tracks the assistant messages currently being generated so they can be aborted.
+pub struct GenerationRegistry {
+    /// Conversations that can't start a generation, with the handle aborting the one running.
+    in_flight: Mutex<HashMap<Uuid, Option<AbortHandle>>>,
+}
+impl GenerationRegistry {
     pub fn new() -> Self {
         Self {
-            conversations: HashMap::new(),
+            in_flight: Mutex::new(HashMap::new()),
         }
     }
-    pub fn from_disk(path: &str) -> Result<Self, std::io::Error> {
-        let file = std::fs::File::open(path)?;
-        let conversations: HashMap<Uuid, Conversation> = serde_json::from_reader(file)?;
-        Ok(Self { conversations })
+    /// Registers a generation for the conversation, it is unregistered when the guard is dropped.
+    pub fn register(
+        &self,
+        conversation_id: Uuid,
+    ) -> Result<(GenerationGuard<'_>, AbortRegistration), MyError> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.contains_key(&conversation_id) {
+            return Err(MyError::GenerationInProgressFail { conversation_id });
+        }
+        let (handle, registration) = AbortHandle::new_pair();
+        in_flight.insert(conversation_id, Some(handle));
+        Ok((
+            GenerationGuard {
+                registry: self,
+                conversation_id,
+            },
+            registration,
+        ))
     }
-    pub fn write_to_disk(&self, path: &str) -> Result<(), std::io::Error> {
-        let file = std::fs::File::create(path)?;
-        serde_json::to_writer(file, &self.conversations)?;
+    /// Keeps generations out of the conversation while its branches change, until the guard is
+    /// dropped. There is nothing to cancel meanwhile.
+    pub fn lock(&self, conversation_id: Uuid) -> Result<GenerationGuard<'_>, MyError> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.contains_key(&conversation_id) {
+            return Err(MyError::GenerationInProgressFail { conversation_id });
+        }
+        in_flight.insert(conversation_id, None);
+        Ok(GenerationGuard {
+            registry: self,
+            conversation_id,
+        })
+    }
+    pub fn cancel(&self, conversation_id: &Uuid) -> Result<(), MyError> {
+        let in_flight = self.in_flight.lock().unwrap();
+        let handle = in_flight
+            .get(conversation_id)
+            .and_then(Option::as_ref)
+            .ok_or(MyError::NoGenerationInProgressFail {
+                conversation_id: *conversation_id,
+            })?;
+        handle.abort();
         Ok(())
     }
 }
+
+pub struct GenerationGuard<'a> {
+    registry: &'a GenerationRegistry,
+    conversation_id: Uuid,
+}
+impl Drop for GenerationGuard<'_> {
+    fn drop(&mut self) {
+        self.registry
+            .in_flight
+            .lock()
+            .unwrap()
+            .remove(&self.conversation_id);
+    }
+}
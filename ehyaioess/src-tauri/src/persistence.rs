@@ -0,0 +1,121 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Path of the copy kept of the previous version of `path`.
+pub fn backup_path(path: &str) -> PathBuf {
+    with_suffix(Path::new(path), ".bak")
+}
+
+/// Path of the append-only log holding the changes made since `path` was last written.
+pub fn event_log_path(path: &str) -> PathBuf {
+    with_suffix(Path::new(path), ".log")
+}
+
+/// Path of the file holding the embeddings computed for the conversations saved at `path`.
+pub fn embeddings_path(path: &str) -> PathBuf {
+    with_suffix(Path::new(path), ".embeddings")
+}
+
+/// Path of the file holding the usage of the requests made for the conversations saved at `path`.
+pub fn usage_path(path: &str) -> PathBuf {
+    with_suffix(Path::new(path), ".usage")
+}
+
+/// Appends each value as one line of JSON and syncs the file before returning.
+pub fn append_json_lines<T: Serialize>(path: &Path, values: &[T]) -> io::Result<()> {
+    if values.is_empty() {
+        return Ok(());
+    }
+    // Built up front so the lines go out in a single write.
+    let mut buffer = Vec::new();
+    for value in values {
+        serde_json::to_writer(&mut buffer, value)?;
+        buffer.push(b'\n');
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&buffer)?;
+    file.sync_data()
+}
+
+/// Empties the file at `path`, creating it if needed.
+pub fn truncate(path: &Path) -> io::Result<()> {
+    File::create(path)?.sync_all()
+}
+
+/// Replaces the file at `path` with `value` as JSON, without ever leaving it partially written.
+///
+/// The JSON goes to a temporary file next to `path` which is synced and then renamed over it,
+/// so a crash or a full disk leaves either the old or the new file in place. The previous
+/// version is copied to `<path>.bak` first.
+pub fn write_json_atomically<T: Serialize>(path: impl AsRef<Path>, value: &T) -> io::Result<()> {
+    write_atomically(path.as_ref(), |writer| serde_json::to_writer(writer, value))
+}
+
+/// Same as `write_json_atomically`, but indented for files meant to be edited by hand.
+pub fn write_pretty_json_atomically<T: Serialize>(
+    path: impl AsRef<Path>,
+    value: &T,
+) -> io::Result<()> {
+    write_atomically(path.as_ref(), |writer| {
+        serde_json::to_writer_pretty(writer, value)
+    })
+}
+
+fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> serde_json::Result<()>,
+) -> io::Result<()> {
+    let temp_path = with_suffix(path, ".tmp");
+
+    let file = File::create(&temp_path)?;
+    let mut writer = BufWriter::new(file);
+    write(&mut writer)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    drop(writer);
+
+    if path.exists() {
+        fs::copy(path, with_suffix(path, ".bak"))?;
+    }
+    fs::rename(&temp_path, path)?;
+
+    // The rename itself is only durable once the directory entry is synced.
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_json_atomically() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data.json");
+        let path = path.to_str().unwrap();
+
+        write_json_atomically(path, &vec![1]).unwrap();
+        assert!(!backup_path(path).exists());
+        write_json_atomically(path, &vec![1, 2]).unwrap();
+
+        assert_eq!(fs::read_to_string(path).unwrap(), "[1,2]");
+        assert_eq!(fs::read_to_string(backup_path(path)).unwrap(), "[1]");
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+use std::pin::Pin;
+
+use async_trait::async_trait;
+use futures_util::Stream;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::models::{ChatRole, ConversationSettings, MyError};
+
+pub mod openai_compatible;
+pub mod retry;
+
+pub use self::openai_compatible::OpenAICompatibleProvider;
+pub use self::retry::{OnRetry, RetryNotice, RetryPolicy};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ChatMessage {
+    pub role: ChatRole,
+    pub content: String,
+}
+
+/// Token counts reported by the server for a completion.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct ChatUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChatCompletion {
+    pub model: String,
+    pub content: String,
+    /// `None` when the server doesn't report usage.
+    pub usage: Option<ChatUsage>,
+}
+
+/// Embedding vectors, one per input in the same order.
+#[derive(Debug, Clone)]
+pub struct Embeddings {
+    pub vectors: Vec<Vec<f32>>,
+    /// `None` when the server doesn't report usage.
+    pub prompt_tokens: Option<u32>,
+}
+
+/// A piece of a streamed completion.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChatCompletionChunk {
+    Delta(String),
+    /// Sent after the last delta by servers that report usage.
+    Usage(ChatUsage),
+}
+
+/// Chunks of a streamed completion, in the order they were received.
+pub type ChatCompletionStream =
+    Pin<Box<dyn Stream<Item = Result<ChatCompletionChunk, MyError>> + Send>>;
+
+/// A backend able to produce chat completions and embeddings.
+///
+/// Commands only talk to the managed `Arc<dyn ChatProvider>`, so adding a backend doesn't touch them.
+/// Completions call `on_retry` before retrying a request that failed for a transient reason.
+#[async_trait]
+pub trait ChatProvider: Send + Sync {
+    async fn complete(
+        &self,
+        messages: &[ChatMessage],
+        settings: &ConversationSettings,
+        on_retry: &OnRetry<'_>,
+    ) -> Result<ChatCompletion, MyError>;
+    async fn complete_streaming(
+        &self,
+        messages: &[ChatMessage],
+        settings: &ConversationSettings,
+        on_retry: &OnRetry<'_>,
+    ) -> Result<ChatCompletionStream, MyError>;
+    async fn list_models(&self) -> Result<Vec<String>, MyError>;
+    /// Computes one embedding vector per input, in the same order.
+    async fn embed(&self, model: &str, input: &[String]) -> Result<Embeddings, MyError>;
+}
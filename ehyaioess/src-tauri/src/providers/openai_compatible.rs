@@ -0,0 +1,453 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use eventsource_stream::Eventsource;
+use futures_util::{future, StreamExt};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION},
+    Url,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    retry::{send_with_retries, OnRetry, RetryPolicy},
+    ChatCompletion, ChatCompletionChunk, ChatCompletionStream, ChatMessage, ChatProvider,
+    ChatUsage, Embeddings,
+};
+use crate::models::{ChatRole, ConversationSettings, MyError, ProviderError};
+
+#[derive(Serialize)]
+struct CompletionRequest<'a> {
+    model: &'a str,
+    messages: Vec<RequestMessage<'a>>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+}
+
+#[derive(Serialize)]
+struct StreamOptions {
+    /// Asks for a last chunk holding the usage of the whole completion.
+    include_usage: bool,
+}
+
+#[derive(Serialize)]
+struct RequestMessage<'a> {
+    role: &'static str,
+    content: &'a str,
+}
+
+impl<'a> From<&'a ChatMessage> for RequestMessage<'a> {
+    fn from(message: &'a ChatMessage) -> Self {
+        RequestMessage {
+            role: match message.role {
+                ChatRole::System => "system",
+                ChatRole::Assistant => "assistant",
+                ChatRole::User => "user",
+            },
+            content: &message.content,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CompletionResponse {
+    model: String,
+    choices: Vec<CompletionChoice>,
+    usage: Option<ResponseUsage>,
+}
+
+#[derive(Deserialize)]
+struct ResponseUsage {
+    prompt_tokens: u32,
+    completion_tokens: u32,
+}
+
+impl From<ResponseUsage> for ChatUsage {
+    fn from(usage: ResponseUsage) -> Self {
+        ChatUsage {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CompletionChoice {
+    message: ResponseMessage,
+}
+
+#[derive(Deserialize)]
+struct ResponseMessage {
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct CompletionChunk {
+    choices: Vec<CompletionChunkChoice>,
+    usage: Option<ResponseUsage>,
+}
+
+#[derive(Deserialize)]
+struct CompletionChunkChoice {
+    delta: CompletionChunkDelta,
+}
+
+#[derive(Deserialize)]
+struct CompletionChunkDelta {
+    content: Option<String>,
+}
+
+#[derive(Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+    usage: Option<EmbeddingUsage>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingUsage {
+    prompt_tokens: u32,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+#[derive(Deserialize)]
+struct ModelList {
+    data: Vec<Model>,
+}
+
+#[derive(Deserialize)]
+struct Model {
+    id: String,
+}
+
+/// Talks to the OpenAI API, or any server exposing the same `/chat/completions`, `/embeddings` and
+/// `/models` routes.
+pub struct OpenAICompatibleProvider {
+    http: reqwest::Client,
+    base_url: Url,
+    model: String,
+    retry_policy: RetryPolicy,
+}
+impl OpenAICompatibleProvider {
+    pub fn new(
+        base_url: &str,
+        api_key: &str,
+        extra_headers: &HashMap<String, String>,
+        model: &str,
+        retry_policy: RetryPolicy,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        // Without the trailing slash, joining would replace the last path segment instead of appending.
+        let mut base_url = Url::parse(base_url)?;
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+
+        let mut headers = HeaderMap::new();
+        // Local servers usually don't check the key, so it is optional.
+        if !api_key.is_empty() {
+            headers.insert(
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!("Bearer {}", api_key))?,
+            );
+        }
+        for (name, value) in extra_headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        let http = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()?;
+
+        Ok(Self {
+            http,
+            base_url,
+            model: model.to_string(),
+            retry_policy,
+        })
+    }
+
+    fn url(&self, path: &str) -> Result<Url, ProviderError> {
+        self.base_url
+            .join(path)
+            .map_err(|e| ProviderError::new(e.to_string()))
+    }
+
+    async fn send_completion_request(
+        &self,
+        messages: &[ChatMessage],
+        settings: &ConversationSettings,
+        stream: bool,
+        on_retry: &OnRetry<'_>,
+    ) -> Result<reqwest::Response, MyError> {
+        let url = self
+            .url("chat/completions")
+            .map_err(MyError::ConversationAIResponseFail)?;
+        let request = CompletionRequest {
+            model: settings.model.as_deref().unwrap_or(&self.model),
+            messages: messages.iter().map(Into::into).collect(),
+            stream,
+            temperature: settings.temperature,
+            top_p: settings.top_p,
+            max_tokens: settings.max_tokens,
+            presence_penalty: settings.presence_penalty,
+            frequency_penalty: settings.frequency_penalty,
+            stream_options: stream.then_some(StreamOptions {
+                include_usage: true,
+            }),
+        };
+        send_with_retries(
+            &self.retry_policy,
+            || self.http.post(url.clone()).json(&request),
+            on_retry,
+        )
+        .await
+        .map_err(MyError::ConversationAIResponseFail)
+    }
+}
+
+#[async_trait]
+impl ChatProvider for OpenAICompatibleProvider {
+    async fn complete(
+        &self,
+        messages: &[ChatMessage],
+        settings: &ConversationSettings,
+        on_retry: &OnRetry<'_>,
+    ) -> Result<ChatCompletion, MyError> {
+        let response: CompletionResponse = self
+            .send_completion_request(messages, settings, false, on_retry)
+            .await?
+            .json()
+            .await
+            .map_err(|e| MyError::ConversationAIResponseFail(e.into()))?;
+        let choice = response.choices.into_iter().next().ok_or_else(|| {
+            MyError::ConversationAIResponseFail(ProviderError::new("the response has no choices"))
+        })?;
+        Ok(ChatCompletion {
+            model: response.model,
+            content: choice.message.content,
+            usage: response.usage.map(Into::into),
+        })
+    }
+
+    async fn complete_streaming(
+        &self,
+        messages: &[ChatMessage],
+        settings: &ConversationSettings,
+        on_retry: &OnRetry<'_>,
+    ) -> Result<ChatCompletionStream, MyError> {
+        let events = self
+            .send_completion_request(messages, settings, true, on_retry)
+            .await?
+            .bytes_stream()
+            .eventsource();
+        let deltas = events
+            .take_while(|event| {
+                future::ready(!matches!(event, Ok(event) if event.data == "[DONE]"))
+            })
+            .filter_map(|event| async move {
+                let chunk = match event {
+                    Ok(event) => serde_json::from_str::<CompletionChunk>(&event.data)
+                        .map_err(|e| ProviderError::new(format!("invalid chunk: {}", e))),
+                    // The connection dropped halfway through the reply.
+                    Err(e) => Err(ProviderError {
+                        status: None,
+                        message: e.to_string(),
+                        retryable: true,
+                    }),
+                };
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(e) => return Some(Err(MyError::ConversationAIResponseFail(e))),
+                };
+                // Usage comes in a chunk of its own, or along with an empty delta.
+                let content = chunk
+                    .choices
+                    .into_iter()
+                    .next()
+                    .and_then(|choice| choice.delta.content)
+                    .filter(|content| !content.is_empty());
+                match (content, chunk.usage) {
+                    (Some(content), _) => Some(Ok(ChatCompletionChunk::Delta(content))),
+                    (None, Some(usage)) => Some(Ok(ChatCompletionChunk::Usage(usage.into()))),
+                    (None, None) => None,
+                }
+            });
+        Ok(Box::pin(deltas))
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, MyError> {
+        let url = self.url("models").map_err(MyError::ListModelsFail)?;
+        let models: ModelList =
+            send_with_retries(&self.retry_policy, || self.http.get(url.clone()), &|_| {})
+                .await
+                .map_err(MyError::ListModelsFail)?
+                .json()
+                .await
+                .map_err(|e| MyError::ListModelsFail(e.into()))?;
+        Ok(models.data.into_iter().map(|model| model.id).collect())
+    }
+
+    async fn embed(&self, model: &str, input: &[String]) -> Result<Embeddings, MyError> {
+        let url = self.url("embeddings").map_err(MyError::EmbeddingFail)?;
+        let request = EmbeddingRequest { model, input };
+        let mut response: EmbeddingResponse = send_with_retries(
+            &self.retry_policy,
+            || self.http.post(url.clone()).json(&request),
+            &|_| {},
+        )
+        .await
+        .map_err(MyError::EmbeddingFail)?
+        .json()
+        .await
+        .map_err(|e| MyError::EmbeddingFail(e.into()))?;
+        if response.data.len() != input.len() {
+            return Err(MyError::EmbeddingFail(ProviderError::new(format!(
+                "expected {} embeddings, got {}",
+                input.len(),
+                response.data.len()
+            ))));
+        }
+        // Servers aren't required to keep the inputs' order.
+        response.data.sort_by_key(|data| data.index);
+        Ok(Embeddings {
+            vectors: response
+                .data
+                .into_iter()
+                .map(|data| data.embedding)
+                .collect(),
+            prompt_tokens: response.usage.map(|usage| usage.prompt_tokens),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::providers::RetryNotice;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::Mutex,
+        time::Duration,
+    };
+
+    /// Serves `responses` in order, one per connection, on a local port.
+    fn mock_server(responses: Vec<String>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+        });
+        format!("http://{}/v1", address)
+    }
+
+    fn response(status: &str, headers: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}\r\n{}",
+            status,
+            body.len(),
+            headers,
+            body
+        )
+    }
+
+    #[test]
+    fn test_complete_retries() {
+        let completion = r#"{"model":"mock","choices":[{"message":{"content":"Hello"}}]}"#;
+        let base_url = mock_server(vec![
+            response("429 Too Many Requests", "Retry-After: 0\r\n", "{}"),
+            response("503 Service Unavailable", "", "{}"),
+            response("200 OK", "", completion),
+            response(
+                "400 Bad Request",
+                "",
+                r#"{"error":{"message":"Unknown model"}}"#,
+            ),
+        ]);
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            timeout: Duration::from_secs(5),
+        };
+        let provider =
+            OpenAICompatibleProvider::new(&base_url, "", &HashMap::new(), "mock", policy).unwrap();
+        let messages = [ChatMessage {
+            role: ChatRole::User,
+            content: "Hi".to_string(),
+        }];
+        let notices = Mutex::new(Vec::new());
+        let on_retry = |notice: &RetryNotice| {
+            notices.lock().unwrap().push(notice.clone());
+        };
+
+        let settings = ConversationSettings::default();
+        let completion =
+            tauri::async_runtime::block_on(provider.complete(&messages, &settings, &on_retry))
+                .unwrap();
+        assert_eq!(completion.content, "Hello");
+        let notices = notices.into_inner().unwrap();
+        assert_eq!(
+            notices
+                .iter()
+                .map(|notice| notice.attempt)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(notices[0].delay, Duration::ZERO);
+
+        // Client errors aren't retried
+        let failed =
+            tauri::async_runtime::block_on(
+                provider.complete(&messages, &settings, &|_| panic!("retried a client error")),
+            );
+        let Err(MyError::ConversationAIResponseFail(error)) = failed else {
+            panic!("expected a response failure, got {:?}", failed);
+        };
+        assert_eq!(error.status, Some(400));
+        assert!(!error.retryable);
+        assert!(error.message.ends_with("Unknown model"));
+    }
+}
@@ -0,0 +1,192 @@
+use std::time::Duration;
+
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    RequestBuilder, Response, StatusCode,
+};
+
+use crate::models::ProviderError;
+
+/// How requests to the provider are retried when they fail in a way that may go away on its own:
+/// rate limiting, server errors and timeouts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    /// Longest wait between attempts, a server asking for a longer one fails the request instead.
+    pub max_backoff: Duration,
+    /// How long to wait for the response to start before the attempt counts as timed out.
+    pub timeout: Duration,
+}
+impl RetryPolicy {
+    /// How long to wait after attempt number `attempt` failed, `None` when it shouldn't be retried.
+    ///
+    /// The exponential backoff is fully jittered so clients rate limited together don't retry
+    /// together, a `Retry-After` from the server is followed as is.
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        if let Some(retry_after) = retry_after {
+            return (retry_after <= self.max_backoff).then_some(retry_after);
+        }
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_backoff);
+        Some(backoff.mul_f64(fastrand::f64()))
+    }
+}
+
+/// Sent before waiting to retry a failed attempt.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryNotice {
+    /// The attempt that failed, starting at 1.
+    pub attempt: u32,
+    pub max_attempts: u32,
+    pub delay: Duration,
+    pub reason: String,
+}
+
+/// Called before each retry.
+pub type OnRetry<'a> = dyn Fn(&RetryNotice) + Send + Sync + 'a;
+
+/// The wait asked for by a `retry-after-ms` or `Retry-After` header, in seconds or as an HTTP date.
+pub fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    if let Some(millis) = header("retry-after-ms").and_then(|value| value.trim().parse().ok()) {
+        return Some(Duration::from_millis(millis));
+    }
+    let value = header(RETRY_AFTER.as_str())?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let wait = date.timestamp_millis() - chrono::Utc::now().timestamp_millis();
+    Some(Duration::from_millis(wait.max(0) as u64))
+}
+
+fn is_transient(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status.is_server_error()
+}
+
+impl From<reqwest::Error> for ProviderError {
+    fn from(e: reqwest::Error) -> Self {
+        ProviderError {
+            status: e.status().map(|status| status.as_u16()),
+            retryable: e.is_timeout() || e.is_connect() || e.status().is_some_and(is_transient),
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Most characters of an error response kept in the message.
+const MAX_ERROR_BODY_CHARS: usize = 500;
+
+/// Describes an error response, with the explanation from its body when there is one.
+async fn response_error(response: Response) -> ProviderError {
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    // OpenAI-compatible servers explain errors in `{"error": {"message": ...}}`.
+    let detail = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|body| body["error"]["message"].as_str().map(str::to_string))
+        .unwrap_or(body);
+    let detail: String = detail.trim().chars().take(MAX_ERROR_BODY_CHARS).collect();
+    let mut message = format!("the server responded with {}", status);
+    if !detail.is_empty() {
+        message = format!("{}: {}", message, detail);
+    }
+    ProviderError {
+        status: Some(status.as_u16()),
+        message,
+        retryable: is_transient(status),
+    }
+}
+
+/// Sends the request built by `request` until it succeeds or the policy gives up.
+///
+/// Only getting the response is retried, a stream failing halfway through isn't. The error
+/// describes why the last attempt failed.
+pub async fn send_with_retries(
+    policy: &RetryPolicy,
+    request: impl Fn() -> RequestBuilder,
+    on_retry: &OnRetry<'_>,
+) -> Result<Response, ProviderError> {
+    let mut attempt = 1;
+    loop {
+        let (error, retry_after) =
+            match tokio::time::timeout(policy.timeout, request().send()).await {
+                Ok(Ok(response)) if response.status().is_success() => return Ok(response),
+                Ok(Ok(response)) => {
+                    let retry_after = parse_retry_after(response.headers());
+                    (response_error(response).await, retry_after)
+                }
+                Ok(Err(e)) => (e.into(), None),
+                Err(_) => (
+                    ProviderError {
+                        status: None,
+                        message: "the server took too long to respond".to_string(),
+                        retryable: true,
+                    },
+                    None,
+                ),
+            };
+        if !error.retryable {
+            return Err(error);
+        }
+        let Some(delay) = policy.delay(attempt, retry_after) else {
+            return Err(error);
+        };
+        on_retry(&RetryNotice {
+            attempt,
+            max_attempts: policy.max_attempts,
+            delay,
+            reason: error.message,
+        });
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_retry_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+            timeout: Duration::from_secs(1),
+        };
+        for _ in 0..20 {
+            assert!(policy.delay(1, None).unwrap() <= Duration::from_millis(100));
+            assert!(policy.delay(4, None).unwrap() <= Duration::from_millis(300));
+        }
+        assert_eq!(policy.delay(5, None), None);
+        // The server's wait is followed, unless it is longer than the policy allows
+        let wait = Duration::from_millis(250);
+        assert_eq!(policy.delay(1, Some(wait)), Some(wait));
+        assert_eq!(policy.delay(1, Some(Duration::from_secs(1))), None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("2"));
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(2)));
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert_eq!(parse_retry_after(&headers), Some(Duration::ZERO));
+        headers.insert("retry-after-ms", HeaderValue::from_static("150"));
+        assert_eq!(
+            parse_retry_after(&headers),
+            Some(Duration::from_millis(150))
+        );
+    }
+}
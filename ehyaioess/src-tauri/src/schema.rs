@@ -0,0 +1,106 @@
+use std::io::{self, ErrorKind};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Upgrades a value by one version, the migration at index `i` of a chain turns version `i` into `i + 1`.
+pub type Migration = fn(&mut Value);
+
+/// Version of the conversation event records, wherever they are saved.
+pub const CONVERSATIONS_VERSION: u32 = 2;
+/// Migrations of a single `ConversationEventRecord`.
+pub const CONVERSATION_RECORD_MIGRATIONS: [Migration; CONVERSATIONS_VERSION as usize] = [
+    // Records didn't change, files only started being wrapped in a `Versioned`.
+    |_| {},
+    // Records didn't change, SQLite started saving search postings.
+    |_| {},
+];
+
+pub const CONFIG_VERSION: u32 = 1;
+/// Migrations of the whole `Config`.
+pub const CONFIG_MIGRATIONS: [Migration; CONFIG_VERSION as usize] = [
+    // Every field added since the first version has a default.
+    |_| {},
+];
+
+/// A file's content tagged with the version it was written in.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Versioned<T> {
+    pub version: u32,
+    pub data: T,
+}
+
+/// Splits a file into its version and content, files from before versioning are version 0.
+pub fn unwrap_versioned(value: Value) -> io::Result<(u32, Value)> {
+    match value {
+        Value::Object(mut map)
+            if map.len() == 2 && map.contains_key("version") && map.contains_key("data") =>
+        {
+            let version = parse_version(&map["version"])?;
+            Ok((version, map.remove("data").unwrap()))
+        }
+        value => Ok((0, value)),
+    }
+}
+
+pub fn parse_version(value: &Value) -> io::Result<u32> {
+    value
+        .as_u64()
+        .and_then(|version| u32::try_from(version).ok())
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Invalid schema version"))
+}
+
+/// Runs the migrations needed to bring `value` from `version` to the latest version.
+pub fn migrate(value: &mut Value, version: u32, migrations: &[Migration]) -> io::Result<()> {
+    let pending = migrations.get(version as usize..).ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Schema version {} was written by a newer version of the app",
+                version
+            ),
+        )
+    })?;
+    for migration in pending {
+        migration(value);
+    }
+    Ok(())
+}
+
+/// Migrates every record of every conversation in a conversation map.
+pub fn migrate_conversations(conversations: &mut Value, version: u32) -> io::Result<()> {
+    let Some(conversations) = conversations.as_object_mut() else {
+        return Ok(());
+    };
+    for conversation in conversations.values_mut() {
+        if let Some(history) = conversation
+            .get_mut("history")
+            .and_then(Value::as_array_mut)
+        {
+            for record in history {
+                migrate(record, version, &CONVERSATION_RECORD_MIGRATIONS)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unwrap_versioned() {
+        let (version, data) = unwrap_versioned(serde_json::json!({ "a": 1 })).unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(data, serde_json::json!({ "a": 1 }));
+
+        let (version, data) =
+            unwrap_versioned(serde_json::json!({ "version": 1, "data": [] })).unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(data, serde_json::json!([]));
+
+        let mut value = Value::Null;
+        assert!(migrate(&mut value, CONFIG_VERSION + 1, &CONFIG_MIGRATIONS).is_err());
+    }
+}
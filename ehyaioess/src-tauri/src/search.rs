@@ -0,0 +1,479 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+};
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use uuid::Uuid;
+
+use crate::{
+    models::{
+        deserialize_optional_timestamp, serialize_optional_timestamp, serialize_timestamp,
+        ChatRole, Conversation, ConversationEvent, ConversationEventRecord,
+        ConversationMessageAlternativeAddedEvent, ConversationMessageEditedEvent,
+        ConversationStatus, ConversationSummary,
+    },
+    storage::ConversationStore,
+};
+
+/// Most results a search returns, newest first.
+const MAX_SEARCH_RESULTS: usize = 100;
+/// Characters of context kept before the first match in a snippet.
+const SNIPPET_CONTEXT: usize = 60;
+/// Length of a snippet in characters.
+const SNIPPET_LENGTH: usize = 200;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, Type)]
+pub struct SearchFilters {
+    /// Only match messages from this author, titles are left out when set.
+    #[serde(default)]
+    pub author: Option<ChatRole>,
+    /// Only match text added at or after this timestamp.
+    #[serde(
+        default,
+        serialize_with = "serialize_optional_timestamp",
+        deserialize_with = "deserialize_optional_timestamp"
+    )]
+    pub from: Option<i64>,
+    /// Only match text added at or before this timestamp.
+    #[serde(
+        default,
+        serialize_with = "serialize_optional_timestamp",
+        deserialize_with = "deserialize_optional_timestamp"
+    )]
+    pub to: Option<i64>,
+    /// Only search conversations with this status, active and archived ones when unset.
+    #[serde(default)]
+    pub status: Option<ConversationStatus>,
+}
+
+/// Part of a snippet to highlight, in UTF-16 code units so it can be passed to `String.slice`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Type)]
+pub struct HighlightRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct SearchResult {
+    pub conversation_id: Uuid,
+    pub conversation_title: String,
+    /// Id of the record that added the matching text.
+    pub event_id: Uuid,
+    /// Author of the matching message, `None` when the title matched.
+    pub role: Option<ChatRole>,
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub timestamp: i64,
+    pub snippet: String,
+    pub highlights: Vec<HighlightRange>,
+}
+
+/// A piece of searchable text, either a message variant or a title.
+///
+/// The text itself stays in the record that added it, and is only read for the results.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchDocument {
+    /// Id of the record that added the text.
+    pub event_id: Uuid,
+    pub conversation_id: Uuid,
+    /// Author of the message, `None` for a title.
+    pub role: Option<ChatRole>,
+    pub timestamp: i64,
+}
+
+/// Inverted index over the message contents and titles of every conversation, held in memory.
+///
+/// It is kept up to date one record at a time as changes are saved, rather than rebuilt. The
+/// JSON storage searches with it, SQLite saves the same postings in its own tables.
+#[derive(Default)]
+pub struct SearchIndex {
+    documents: HashMap<Uuid, (SearchDocument, String)>,
+    /// Ids of the documents containing each token.
+    postings: BTreeMap<String, HashSet<Uuid>>,
+    /// Ids of the documents of each conversation.
+    conversation_documents: HashMap<Uuid, Vec<Uuid>>,
+    /// Id of the document holding each conversation's current title.
+    titles: HashMap<Uuid, Uuid>,
+}
+
+/// Splits text into lowercase alphanumeric tokens, with the byte range each one came from.
+pub fn tokenize(text: &str) -> Vec<(String, usize, usize)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices().chain([(text.len(), ' ')]) {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(i),
+            (false, Some(token_start)) => {
+                tokens.push((text[token_start..i].to_lowercase(), token_start, i));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    tokens
+}
+
+/// Whether `token` matches a query, the last query token also matches as a prefix so results
+/// show up while it is being typed.
+fn matches_query(token: &str, query: &[String]) -> bool {
+    query.iter().enumerate().any(|(i, query_token)| {
+        if i == query.len() - 1 {
+            token.starts_with(query_token.as_str())
+        } else {
+            token == query_token
+        }
+    })
+}
+
+fn utf16_len(text: &str) -> u32 {
+    text.encode_utf16().count() as u32
+}
+
+/// Cuts a window of `text` around the first match, with the matches in it.
+fn snippet(text: &str, query: &[String]) -> (String, Vec<HighlightRange>) {
+    let matches: Vec<(usize, usize)> = tokenize(text)
+        .into_iter()
+        .filter(|(token, _, _)| matches_query(token, query))
+        .map(|(_, start, end)| (start, end))
+        .collect();
+    let first_match = matches.first().map_or(0, |(start, _)| *start);
+    let start = text[..first_match]
+        .char_indices()
+        .rev()
+        .nth(SNIPPET_CONTEXT - 1)
+        .map_or(0, |(i, _)| i);
+    let end = text[start..]
+        .char_indices()
+        .nth(SNIPPET_LENGTH)
+        .map_or(text.len(), |(i, _)| start + i);
+
+    let highlights = matches
+        .into_iter()
+        .filter(|(match_start, match_end)| *match_start >= start && *match_end <= end)
+        .map(|(match_start, match_end)| HighlightRange {
+            start: utf16_len(&text[start..match_start]),
+            end: utf16_len(&text[start..match_end]),
+        })
+        .collect();
+    (text[start..end].to_string(), highlights)
+}
+
+/// The tokens of a search query, the last one matches as a prefix.
+pub fn query_tokens(query: &str) -> Vec<String> {
+    tokenize(query)
+        .into_iter()
+        .map(|(token, _, _)| token)
+        .collect()
+}
+
+/// Describes a document as a result, its snippet is cut around the first match of `query`.
+pub fn result_for(
+    document: SearchDocument,
+    text: &str,
+    query: &[String],
+    conversation_title: String,
+) -> SearchResult {
+    let (snippet, highlights) = snippet(text, query);
+    SearchResult {
+        conversation_id: document.conversation_id,
+        conversation_title,
+        event_id: document.event_id,
+        role: document.role,
+        timestamp: document.timestamp,
+        snippet,
+        highlights,
+    }
+}
+
+/// Finds the saved documents containing every word of the query, newest first.
+///
+/// Statuses and titles come from the conversation summaries, and only the text of the results
+/// that are returned gets read.
+pub fn search(
+    store: &dyn ConversationStore,
+    summaries: &HashMap<Uuid, ConversationSummary>,
+    query: &str,
+    filters: &SearchFilters,
+) -> io::Result<Vec<SearchResult>> {
+    let query = query_tokens(query);
+    if query.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut documents: Vec<SearchDocument> = store
+        .find_documents(&query)?
+        .into_iter()
+        .filter(|document| {
+            let Some(summary) = summaries.get(&document.conversation_id) else {
+                return false;
+            };
+            let status_matches = match filters.status {
+                Some(wanted) => summary.status == wanted,
+                None => summary.status != ConversationStatus::Trashed,
+            };
+            status_matches
+                && filters
+                    .author
+                    .map_or(true, |author| document.role == Some(author))
+                && filters.from.map_or(true, |from| document.timestamp >= from)
+                && filters.to.map_or(true, |to| document.timestamp <= to)
+        })
+        .collect();
+    documents.sort_by_key(|document| std::cmp::Reverse(document.timestamp));
+    documents.truncate(MAX_SEARCH_RESULTS);
+
+    let ids: Vec<Uuid> = documents.iter().map(|document| document.event_id).collect();
+    let mut loaded = store.load_documents(&ids)?;
+    loaded.sort_by_key(|(document, _)| std::cmp::Reverse(document.timestamp));
+    Ok(loaded
+        .into_iter()
+        .map(|(document, text)| {
+            let title = summaries[&document.conversation_id].title.clone();
+            result_for(document, &text, &query, title)
+        })
+        .collect())
+}
+
+impl SearchIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn add_conversation(&mut self, conversation: &Conversation) {
+        for record in &conversation.history {
+            self.add_record(record);
+        }
+    }
+    pub fn add_record(&mut self, record: &ConversationEventRecord) {
+        let conversation_id = record.conversation_id;
+        let Some(text) = record.event.text() else {
+            return;
+        };
+        let role = match &record.event {
+            ConversationEvent::MessageAdded(event) => Some(event.author),
+            // Variants are searchable as the message they replace, by the same author.
+            ConversationEvent::MessageAlternativeAdded(
+                ConversationMessageAlternativeAddedEvent { message_id, .. },
+            )
+            | ConversationEvent::MessageEdited(ConversationMessageEditedEvent {
+                message_id, ..
+            }) => {
+                let Some((message, _)) = self.documents.get(message_id) else {
+                    return;
+                };
+                message.role
+            }
+            ConversationEvent::TitleChange(_) => {
+                if let Some(previous) = self.titles.insert(conversation_id, record.id) {
+                    self.remove_document(&previous);
+                }
+                None
+            }
+            _ => return,
+        };
+        for (token, _, _) in tokenize(text) {
+            self.postings.entry(token).or_default().insert(record.id);
+        }
+        self.conversation_documents
+            .entry(conversation_id)
+            .or_default()
+            .push(record.id);
+        self.documents.insert(
+            record.id,
+            (
+                SearchDocument {
+                    event_id: record.id,
+                    conversation_id,
+                    role,
+                    timestamp: record.timestamp,
+                },
+                text.to_string(),
+            ),
+        );
+    }
+    fn remove_document(&mut self, id: &Uuid) {
+        let Some((_, text)) = self.documents.remove(id) else {
+            return;
+        };
+        for (token, _, _) in tokenize(&text) {
+            if let Some(ids) = self.postings.get_mut(&token) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.postings.remove(&token);
+                }
+            }
+        }
+    }
+    pub fn remove_conversation(&mut self, conversation_id: &Uuid) {
+        for id in self
+            .conversation_documents
+            .remove(conversation_id)
+            .unwrap_or_default()
+        {
+            self.remove_document(&id);
+        }
+        self.titles.remove(conversation_id);
+    }
+    /// Ids of the documents containing the token, or a token it prefixes when `prefix` is set.
+    fn lookup(&self, token: &str, prefix: bool) -> HashSet<Uuid> {
+        if !prefix {
+            return self.postings.get(token).cloned().unwrap_or_default();
+        }
+        self.postings
+            .range(token.to_string()..)
+            .take_while(|(candidate, _)| candidate.starts_with(token))
+            .flat_map(|(_, ids)| ids.iter().copied())
+            .collect()
+    }
+    /// The documents containing every token of the query.
+    pub fn find(&self, query: &[String]) -> Vec<SearchDocument> {
+        let mut matching: Option<HashSet<Uuid>> = None;
+        for (i, token) in query.iter().enumerate() {
+            let ids = self.lookup(token, i == query.len() - 1);
+            matching = Some(match matching {
+                Some(matching) => matching.intersection(&ids).copied().collect(),
+                None => ids,
+            });
+        }
+        matching
+            .unwrap_or_default()
+            .into_iter()
+            .map(|id| self.documents[&id].0.clone())
+            .collect()
+    }
+    /// Every message variant.
+    pub fn messages(&self) -> Vec<SearchDocument> {
+        self.documents
+            .values()
+            .filter(|(document, _)| document.role.is_some())
+            .map(|(document, _)| document.clone())
+            .collect()
+    }
+    /// The documents with their text, leaving out the ids that aren't indexed.
+    pub fn load(&self, ids: &[Uuid]) -> Vec<(SearchDocument, String)> {
+        ids.iter()
+            .filter_map(|id| self.documents.get(id).cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        models::{
+            ConversationMessageAddedEvent, ConversationStatusChangedEvent,
+            ConversationTitleChangedEvent,
+        },
+        storage::{ConversationChange, JsonEventLogStore, SqliteStore},
+    };
+
+    /// Saves each record as it is added, then searches the way the manager does.
+    fn check_search(store: &dyn ConversationStore) {
+        let mut conv = Conversation::new();
+        store
+            .append(&[ConversationChange::ConversationCreated(conv.id)], &[])
+            .unwrap();
+        let save = |conv: &mut Conversation, event: ConversationEvent| {
+            let record = conv.add_event(event).clone();
+            store
+                .append(&[ConversationChange::EventAdded(record.clone())], &[])
+                .unwrap();
+            record
+        };
+        let add = |conv: &mut Conversation, author, content: &str| {
+            save(
+                conv,
+                ConversationMessageAddedEvent {
+                    author,
+                    content: content.to_string(),
+                    usage: None,
+                }
+                .into(),
+            )
+        };
+        let find = |conv: &Conversation, query: &str, filters: &SearchFilters| {
+            let summaries = HashMap::from([(conv.id, conv.summarize())]);
+            search(store, &summaries, query, filters).unwrap()
+        };
+        let question = add(&mut conv, ChatRole::User, "How do Rust lifetimes work?");
+        add(&mut conv, ChatRole::Assistant, "Lifetimes name scopes.");
+        save(
+            &mut conv,
+            ConversationTitleChangedEvent {
+                new_title: "Rust questions".to_string(),
+                auto_generated: false,
+                usage: None,
+            }
+            .into(),
+        );
+
+        let results = find(&conv, "rust LIFE", &SearchFilters::default());
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].event_id, question.id);
+        assert_eq!(results[0].conversation_title, "Rust questions");
+        assert_eq!(
+            results[0].highlights,
+            vec![
+                HighlightRange { start: 7, end: 11 },
+                HighlightRange { start: 12, end: 21 },
+            ]
+        );
+
+        let filters = SearchFilters {
+            author: Some(ChatRole::Assistant),
+            ..Default::default()
+        };
+        assert_eq!(find(&conv, "lifetimes", &filters).len(), 1);
+        assert_eq!(find(&conv, "rust", &SearchFilters::default()).len(), 2);
+
+        // Edits are searchable, and the title that was replaced no longer is
+        save(
+            &mut conv,
+            ConversationMessageEditedEvent {
+                message_id: question.id,
+                content: "What are borrows?".to_string(),
+            }
+            .into(),
+        );
+        save(
+            &mut conv,
+            ConversationTitleChangedEvent {
+                new_title: "Borrowing".to_string(),
+                auto_generated: false,
+                usage: None,
+            }
+            .into(),
+        );
+        assert_eq!(find(&conv, "borrow", &SearchFilters::default()).len(), 2);
+        assert!(find(&conv, "questions", &SearchFilters::default()).is_empty());
+
+        save(
+            &mut conv,
+            ConversationStatusChangedEvent {
+                status: ConversationStatus::Trashed,
+            }
+            .into(),
+        );
+        assert!(find(&conv, "borrows", &SearchFilters::default()).is_empty());
+        store
+            .append(&[ConversationChange::ConversationRemoved(conv.id)], &[])
+            .unwrap();
+        let filters = SearchFilters {
+            status: Some(ConversationStatus::Trashed),
+            ..Default::default()
+        };
+        assert!(find(&conv, "borrows", &filters).is_empty());
+    }
+
+    #[test]
+    fn test_search() {
+        check_search(&SqliteStore::open(":memory:").unwrap());
+
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        check_search(&JsonEventLogStore::new(
+            dir.join("conversations.json").to_str().unwrap(),
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,1283 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, BufRead, BufReader, ErrorKind},
+    path::Path,
+    sync::Mutex,
+};
+
+use rusqlite::{params, params_from_iter, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::{
+    config::{Config, ConversationStorage},
+    embeddings::StoredEmbedding,
+    models::{
+        ChatRole, Conversation, ConversationEvent, ConversationEventRecord,
+        ConversationMessageAlternativeAddedEvent, ConversationMessageEditedEvent,
+        ConversationSummary,
+    },
+    persistence,
+    schema::{self, Versioned, CONVERSATIONS_VERSION, CONVERSATION_RECORD_MIGRATIONS},
+    search::{tokenize, SearchDocument, SearchIndex},
+    usage::{UsagePeriod, UsageRecord},
+};
+
+/// A change made to the conversations since they were last saved.
+///
+/// The JSON store writes these as the lines of its event log.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ConversationChange {
+    ConversationCreated(Uuid),
+    EventAdded(ConversationEventRecord),
+    ConversationRemoved(Uuid),
+}
+
+/// Where `ConversationManager` loads conversations from and saves their changes to.
+pub trait ConversationStore: Send + Sync {
+    /// Loads every conversation, there are none when nothing has been saved yet.
+    fn load(&self) -> io::Result<HashMap<Uuid, Conversation>>;
+    /// Whether `load_index` and `load_conversation` only read what they return.
+    ///
+    /// When they don't, every conversation is loaded with `load` and kept in memory instead.
+    fn loads_lazily(&self) -> bool;
+    /// Loads the summary of every conversation, without keeping their histories around.
+    fn load_index(&self) -> io::Result<HashMap<Uuid, ConversationSummary>>;
+    /// Loads a single conversation, `None` if it was never saved or has been removed.
+    fn load_conversation(&self, id: &Uuid) -> io::Result<Option<Conversation>>;
+    /// Saves the changes, in order, along with the new summaries of the conversations they touched.
+    fn append(
+        &self,
+        changes: &[ConversationChange],
+        summaries: &[ConversationSummary],
+    ) -> io::Result<()>;
+    /// Loads the embeddings saved for message variants, at most one per variant and model.
+    fn load_embeddings(&self) -> io::Result<Vec<StoredEmbedding>>;
+    /// Saves embeddings, replacing those of the same variants and models.
+    fn append_embeddings(&self, embeddings: &[StoredEmbedding]) -> io::Result<()>;
+    /// Rewrites the store so it holds exactly `conversations`, keeping the embeddings of their records.
+    fn compact(&self, conversations: &HashMap<Uuid, Conversation>) -> io::Result<()>;
+    /// The saved message variants and titles containing every token of the query, the last
+    /// token also matching as a prefix. Titles that were replaced are left out.
+    fn find_documents(&self, query: &[String]) -> io::Result<Vec<SearchDocument>>;
+    /// Every saved message variant.
+    fn message_documents(&self) -> io::Result<Vec<SearchDocument>>;
+    /// The documents with the text of the records that added them, leaving out unknown ids.
+    fn load_documents(&self, ids: &[Uuid]) -> io::Result<Vec<(SearchDocument, String)>>;
+    /// The usage of the requests made in `period`, of one conversation or all of them.
+    ///
+    /// Appending a record with usage saves it here too, and it stays when the record is removed.
+    fn load_usage(
+        &self,
+        conversation_id: Option<&Uuid>,
+        period: &UsagePeriod,
+    ) -> io::Result<Vec<UsageRecord>>;
+    /// Saves the usage of requests that aren't saved as records, like embeddings.
+    fn append_usage(&self, usage: &[UsageRecord]) -> io::Result<()>;
+}
+
+/// Opens the store picked in the config, moving the JSON history into SQLite the first time.
+pub fn open_conversation_store(config: &Config) -> io::Result<Box<dyn ConversationStore>> {
+    let json_store = JsonEventLogStore::new(&config.conversation_history_save_path);
+    match config.conversation_storage {
+        ConversationStorage::Json => Ok(Box::new(json_store)),
+        ConversationStorage::Sqlite => {
+            let sqlite_store = SqliteStore::open(&config.conversation_database_path)?;
+            sqlite_store.migrate_from(&json_store)?;
+            Ok(Box::new(sqlite_store))
+        }
+    }
+}
+
+fn sqlite_error(error: rusqlite::Error) -> io::Error {
+    io::Error::new(ErrorKind::Other, error)
+}
+
+/// Returned instead of reading the whole JSON history again for a single conversation.
+fn lazy_loading_unsupported() -> io::Error {
+    io::Error::new(
+        ErrorKind::Unsupported,
+        "the JSON storage only loads every conversation at once",
+    )
+}
+
+/// First line of the event log, logs from before versioning start with a change instead.
+#[derive(Debug, Serialize, Deserialize)]
+struct EventLogHeader {
+    version: u32,
+}
+
+/// A JSON snapshot of every conversation, followed by a log of the changes made since.
+///
+/// Both files can only be read as a whole, so unlike `SqliteStore` it doesn't load lazily. The
+/// search index is held in memory, built by `load` and kept up to date by `append`.
+pub struct JsonEventLogStore {
+    path: String,
+    search_index: Mutex<SearchIndex>,
+}
+impl JsonEventLogStore {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            search_index: Mutex::new(SearchIndex::new()),
+        }
+    }
+    fn exists(&self) -> bool {
+        Path::new(&self.path).exists() || persistence::event_log_path(&self.path).exists()
+    }
+    /// Returns the conversations in the snapshot and the version they were saved in.
+    fn load_snapshot(&self) -> io::Result<(HashMap<Uuid, Conversation>, u32)> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Ok((HashMap::new(), CONVERSATIONS_VERSION))
+            }
+            Err(e) => return Err(e),
+        };
+        let parse = || -> io::Result<_> {
+            let value: Value = serde_json::from_reader(BufReader::new(file))?;
+            let (version, mut data) = schema::unwrap_versioned(value)?;
+            schema::migrate_conversations(&mut data, version)?;
+            Ok((serde_json::from_value(data)?, version))
+        };
+        parse().map_err(|e| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{}: {} (the previous version is kept at {:?})",
+                    self.path,
+                    e,
+                    persistence::backup_path(&self.path)
+                ),
+            )
+        })
+    }
+    /// Replays the event log over the snapshot, also returning whether the two need compacting.
+    ///
+    /// Only the last line of the log may fail to parse, as a crash while appending leaves it torn.
+    fn read(&self) -> io::Result<(HashMap<Uuid, Conversation>, bool)> {
+        let (mut conversations, snapshot_version) = self.load_snapshot()?;
+
+        let lines = match File::open(persistence::event_log_path(&self.path)) {
+            Ok(file) => BufReader::new(file)
+                .lines()
+                .collect::<Result<Vec<_>, _>>()?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        // Records already in the snapshot are skipped, in case compaction stopped before emptying the log.
+        let mut known_ids: HashSet<Uuid> = conversations
+            .values()
+            .flat_map(|conv| conv.history.iter().map(|record| record.id))
+            .collect();
+        let log_version = match lines.first() {
+            Some(line) => match serde_json::from_str::<EventLogHeader>(line) {
+                Ok(header) => header.version,
+                Err(_) => 0,
+            },
+            None => CONVERSATIONS_VERSION,
+        };
+        for (i, line) in lines.iter().enumerate() {
+            let mut value: Value = match serde_json::from_str(line) {
+                Ok(value) => value,
+                Err(_) if i == lines.len() - 1 => break,
+                Err(e) => return Err(e.into()),
+            };
+            if i == 0 && log_version > 0 {
+                continue;
+            }
+            if let Some(record) = value.get_mut("EventAdded") {
+                schema::migrate(record, log_version, &CONVERSATION_RECORD_MIGRATIONS)?;
+            }
+            match serde_json::from_value::<ConversationChange>(value)? {
+                ConversationChange::ConversationCreated(id) => {
+                    conversations.entry(id).or_insert_with(|| Conversation {
+                        id,
+                        history: Vec::new(),
+                    });
+                }
+                ConversationChange::EventAdded(record) => {
+                    if known_ids.insert(record.id) {
+                        if let Some(conv) = conversations.get_mut(&record.conversation_id) {
+                            conv.history.push(record);
+                        }
+                    }
+                }
+                ConversationChange::ConversationRemoved(id) => {
+                    conversations.remove(&id);
+                }
+            }
+        }
+
+        let needs_compaction = !lines.is_empty() || snapshot_version < CONVERSATIONS_VERSION;
+        Ok((conversations, needs_compaction))
+    }
+}
+impl ConversationStore for JsonEventLogStore {
+    /// Reads the snapshot and the event log, then compacts the two.
+    ///
+    /// Compacting right away keeps a torn last line from ending up in the middle of the log, and
+    /// saves whatever was migrated in the latest version.
+    fn load(&self) -> io::Result<HashMap<Uuid, Conversation>> {
+        let (conversations, needs_compaction) = self.read()?;
+        if needs_compaction {
+            self.compact(&conversations)?;
+        }
+        let mut search_index = SearchIndex::new();
+        for conversation in conversations.values() {
+            search_index.add_conversation(conversation);
+        }
+        *self.search_index.lock().unwrap() = search_index;
+        Ok(conversations)
+    }
+    fn loads_lazily(&self) -> bool {
+        false
+    }
+    fn load_index(&self) -> io::Result<HashMap<Uuid, ConversationSummary>> {
+        Err(lazy_loading_unsupported())
+    }
+    fn load_conversation(&self, _id: &Uuid) -> io::Result<Option<Conversation>> {
+        Err(lazy_loading_unsupported())
+    }
+    fn append(
+        &self,
+        changes: &[ConversationChange],
+        _summaries: &[ConversationSummary],
+    ) -> io::Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let log_path = persistence::event_log_path(&self.path);
+        let is_empty = match std::fs::metadata(&log_path) {
+            Ok(metadata) => metadata.len() == 0,
+            Err(e) if e.kind() == ErrorKind::NotFound => true,
+            Err(e) => return Err(e),
+        };
+        if is_empty {
+            persistence::append_json_lines(
+                &log_path,
+                &[EventLogHeader {
+                    version: CONVERSATIONS_VERSION,
+                }],
+            )?;
+        }
+        persistence::append_json_lines(&log_path, changes)?;
+        let usage: Vec<UsageRecord> = changes
+            .iter()
+            .filter_map(|change| match change {
+                ConversationChange::EventAdded(record) => UsageRecord::from_record(record),
+                _ => None,
+            })
+            .collect();
+        self.append_usage(&usage)?;
+
+        let mut search_index = self.search_index.lock().unwrap();
+        for change in changes {
+            match change {
+                ConversationChange::EventAdded(record) => search_index.add_record(record),
+                ConversationChange::ConversationRemoved(id) => search_index.remove_conversation(id),
+                ConversationChange::ConversationCreated(_) => {}
+            }
+        }
+        Ok(())
+    }
+    /// Later lines replace earlier ones for the same variant and model, and a torn last line is skipped.
+    fn load_embeddings(&self) -> io::Result<Vec<StoredEmbedding>> {
+        let lines = match File::open(persistence::embeddings_path(&self.path)) {
+            Ok(file) => BufReader::new(file)
+                .lines()
+                .collect::<Result<Vec<_>, _>>()?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let mut embeddings = HashMap::new();
+        for (i, line) in lines.iter().enumerate() {
+            let embedding: StoredEmbedding = match serde_json::from_str(line) {
+                Ok(embedding) => embedding,
+                Err(_) if i == lines.len() - 1 => break,
+                Err(e) => return Err(e.into()),
+            };
+            embeddings.insert((embedding.event_id, embedding.model.clone()), embedding);
+        }
+        Ok(embeddings.into_values().collect())
+    }
+    fn append_embeddings(&self, embeddings: &[StoredEmbedding]) -> io::Result<()> {
+        persistence::append_json_lines(&persistence::embeddings_path(&self.path), embeddings)
+    }
+    fn compact(&self, conversations: &HashMap<Uuid, Conversation>) -> io::Result<()> {
+        persistence::write_json_atomically(
+            &self.path,
+            &Versioned {
+                version: CONVERSATIONS_VERSION,
+                data: conversations,
+            },
+        )?;
+        persistence::truncate(&persistence::event_log_path(&self.path))
+    }
+    fn find_documents(&self, query: &[String]) -> io::Result<Vec<SearchDocument>> {
+        Ok(self.search_index.lock().unwrap().find(query))
+    }
+    fn message_documents(&self) -> io::Result<Vec<SearchDocument>> {
+        Ok(self.search_index.lock().unwrap().messages())
+    }
+    fn load_documents(&self, ids: &[Uuid]) -> io::Result<Vec<(SearchDocument, String)>> {
+        Ok(self.search_index.lock().unwrap().load(ids))
+    }
+    /// Reads the whole usage file, skipping a torn last line like `load_embeddings`.
+    fn load_usage(
+        &self,
+        conversation_id: Option<&Uuid>,
+        period: &UsagePeriod,
+    ) -> io::Result<Vec<UsageRecord>> {
+        let lines = match File::open(persistence::usage_path(&self.path)) {
+            Ok(file) => BufReader::new(file)
+                .lines()
+                .collect::<Result<Vec<_>, _>>()?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let mut usage = Vec::new();
+        for (i, line) in lines.iter().enumerate() {
+            let record: UsageRecord = match serde_json::from_str(line) {
+                Ok(record) => record,
+                Err(_) if i == lines.len() - 1 => break,
+                Err(e) => return Err(e.into()),
+            };
+            if conversation_id.map_or(true, |id| record.conversation_id == Some(*id))
+                && period.contains(record.timestamp)
+            {
+                usage.push(record);
+            }
+        }
+        Ok(usage)
+    }
+    fn append_usage(&self, usage: &[UsageRecord]) -> io::Result<()> {
+        persistence::append_json_lines(&persistence::usage_path(&self.path), usage)
+    }
+}
+
+/// Upgrades the SQLite tables by one version, like a `schema::Migration` does for the records.
+type TableMigration = fn(&Connection) -> rusqlite::Result<()>;
+/// Migrations of the SQLite tables, run once the event rows are migrated.
+const TABLE_MIGRATIONS: [TableMigration; CONVERSATIONS_VERSION as usize] = [
+    // The tables are created when the database is opened.
+    |_| Ok(()),
+    // Search postings started being saved, the records saved before are indexed once.
+    index_events,
+];
+
+/// Conversations and their event records as rows of a SQLite database.
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+impl SqliteStore {
+    pub fn open(path: &str) -> io::Result<Self> {
+        Self::from_connection(Connection::open(path).map_err(sqlite_error)?)
+    }
+    fn from_connection(connection: Connection) -> io::Result<Self> {
+        connection
+            .execute_batch(
+                "PRAGMA journal_mode = WAL;
+                PRAGMA foreign_keys = ON;
+                CREATE TABLE IF NOT EXISTS conversations (
+                    id TEXT PRIMARY KEY NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS events (
+                    id TEXT PRIMARY KEY NOT NULL,
+                    conversation_id TEXT NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+                    timestamp INTEGER NOT NULL,
+                    event TEXT NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS events_by_conversation
+                    ON events (conversation_id, timestamp);
+                CREATE TABLE IF NOT EXISTS embeddings (
+                    event_id TEXT NOT NULL REFERENCES events(id) ON DELETE CASCADE,
+                    model TEXT NOT NULL,
+                    vector BLOB NOT NULL,
+                    PRIMARY KEY (event_id, model)
+                );
+                CREATE TABLE IF NOT EXISTS conversation_summaries (
+                    id TEXT PRIMARY KEY NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+                    summary TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS search_documents (
+                    event_id TEXT PRIMARY KEY NOT NULL REFERENCES events(id) ON DELETE CASCADE,
+                    conversation_id TEXT NOT NULL,
+                    role TEXT,
+                    timestamp INTEGER NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS search_documents_by_conversation
+                    ON search_documents (conversation_id);
+                CREATE TABLE IF NOT EXISTS search_postings (
+                    token TEXT NOT NULL,
+                    event_id TEXT NOT NULL REFERENCES search_documents(event_id) ON DELETE CASCADE,
+                    PRIMARY KEY (token, event_id)
+                ) WITHOUT ROWID;
+                CREATE INDEX IF NOT EXISTS search_postings_by_event
+                    ON search_postings (event_id);
+                CREATE TABLE IF NOT EXISTS usage (
+                    event_id TEXT UNIQUE,
+                    conversation_id TEXT,
+                    timestamp INTEGER NOT NULL,
+                    usage TEXT NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS usage_by_timestamp ON usage (timestamp);
+                CREATE INDEX IF NOT EXISTS usage_by_conversation
+                    ON usage (conversation_id, timestamp);",
+            )
+            .map_err(sqlite_error)?;
+        let mut store = Self {
+            connection: Mutex::new(connection),
+        };
+        store.migrate()?;
+        Ok(store)
+    }
+    /// Brings the event rows, then the tables derived from them, up to the latest version,
+    /// tracked in SQLite's `user_version`.
+    fn migrate(&mut self) -> io::Result<()> {
+        let connection = self.connection.get_mut().unwrap();
+        let version: i64 = connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .map_err(sqlite_error)?;
+        let version = schema::parse_version(&Value::from(version))?;
+        if version == CONVERSATIONS_VERSION {
+            return Ok(());
+        }
+
+        let transaction = connection.transaction().map_err(sqlite_error)?;
+        let rows = {
+            let mut statement = transaction
+                .prepare("SELECT id, conversation_id, timestamp, event FROM events")
+                .map_err(sqlite_error)?;
+            let rows = statement
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, i64>(2)?,
+                        row.get::<_, String>(3)?,
+                    ))
+                })
+                .map_err(sqlite_error)?;
+            rows.collect::<Result<Vec<_>, _>>().map_err(sqlite_error)?
+        };
+        for (id, conversation_id, timestamp, event) in rows {
+            // Migrations work on whole records, as they are written in the JSON files.
+            let mut record = serde_json::json!({
+                "id": id,
+                "conversation_id": conversation_id,
+                "timestamp": timestamp.to_string(),
+                "event": serde_json::from_str::<Value>(&event)?,
+            });
+            schema::migrate(&mut record, version, &CONVERSATION_RECORD_MIGRATIONS)?;
+            transaction
+                .execute(
+                    "UPDATE events SET event = ?1 WHERE id = ?2",
+                    params![record["event"].to_string(), id],
+                )
+                .map_err(sqlite_error)?;
+        }
+        // Summaries are rebuilt from the migrated records on the next load.
+        transaction
+            .execute("DELETE FROM conversation_summaries", [])
+            .map_err(sqlite_error)?;
+        for migration in TABLE_MIGRATIONS.iter().skip(version as usize) {
+            migration(&transaction).map_err(sqlite_error)?;
+        }
+        transaction
+            .pragma_update(None, "user_version", CONVERSATIONS_VERSION)
+            .map_err(sqlite_error)?;
+        transaction.commit().map_err(sqlite_error)
+    }
+    /// Copies the JSON history into an empty database, then renames the JSON files so it only happens once.
+    fn migrate_from(&self, json_store: &JsonEventLogStore) -> io::Result<()> {
+        if !json_store.exists() {
+            return Ok(());
+        }
+        let is_empty = {
+            let connection = self.connection.lock().unwrap();
+            let count: i64 = connection
+                .query_row("SELECT COUNT(*) FROM conversations", [], |row| row.get(0))
+                .map_err(sqlite_error)?;
+            count == 0
+        };
+        if !is_empty {
+            return Ok(());
+        }
+
+        let conversations = json_store.load()?;
+        self.compact(&conversations)?;
+        self.append_embeddings(&json_store.load_embeddings()?)?;
+        self.append_usage(&json_store.load_usage(None, &UsagePeriod::default())?)?;
+        for path in [
+            Path::new(&json_store.path).to_path_buf(),
+            persistence::event_log_path(&json_store.path),
+            persistence::embeddings_path(&json_store.path),
+            persistence::usage_path(&json_store.path),
+        ] {
+            if path.exists() {
+                let mut migrated = path.clone().into_os_string();
+                migrated.push(".migrated");
+                std::fs::rename(&path, migrated)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn insert_conversation(connection: &Connection, id: &Uuid) -> rusqlite::Result<()> {
+    connection.execute(
+        "INSERT OR IGNORE INTO conversations (id) VALUES (?1)",
+        params![id.to_string()],
+    )?;
+    Ok(())
+}
+
+fn insert_event(connection: &Connection, record: &ConversationEventRecord) -> rusqlite::Result<()> {
+    let event = serde_json::to_string(&record.event)
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    let inserted = connection.execute(
+        "INSERT OR IGNORE INTO events (id, conversation_id, timestamp, event)
+        VALUES (?1, ?2, ?3, ?4)",
+        params![
+            record.id.to_string(),
+            record.conversation_id.to_string(),
+            record.timestamp,
+            event
+        ],
+    )?;
+    if inserted > 0 {
+        index_record(connection, record)?;
+        if let Some(usage) = UsageRecord::from_record(record) {
+            insert_usage(connection, &usage)?;
+        }
+    }
+    Ok(())
+}
+
+/// Skipped when the usage of the same record was already saved, as compacting inserts it again.
+fn insert_usage(connection: &Connection, record: &UsageRecord) -> rusqlite::Result<()> {
+    let usage = serde_json::to_string(&record.usage)
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    connection.execute(
+        "INSERT OR IGNORE INTO usage (event_id, conversation_id, timestamp, usage)
+        VALUES (?1, ?2, ?3, ?4)",
+        params![
+            record.event_id.map(|id| id.to_string()),
+            record.conversation_id.map(|id| id.to_string()),
+            record.timestamp,
+            usage
+        ],
+    )?;
+    Ok(())
+}
+
+/// Saves the search postings of every record, in the order they were added.
+fn index_events(connection: &Connection) -> rusqlite::Result<()> {
+    let mut statement = connection
+        .prepare("SELECT id, conversation_id, timestamp, event FROM events ORDER BY rowid")?;
+    let records = statement
+        .query_map([], read_record)?
+        .collect::<Result<Vec<_>, _>>()?;
+    for record in &records {
+        index_record(connection, record)?;
+    }
+    Ok(())
+}
+
+/// Saves the postings of the text the record added, following the rules of `SearchIndex`.
+fn index_record(connection: &Connection, record: &ConversationEventRecord) -> rusqlite::Result<()> {
+    let Some(text) = record.event.text() else {
+        return Ok(());
+    };
+    let (id, conversation_id) = (record.id.to_string(), record.conversation_id.to_string());
+    let indexed = match &record.event {
+        ConversationEvent::MessageAdded(event) => connection.execute(
+            "INSERT INTO search_documents (event_id, conversation_id, role, timestamp)
+            VALUES (?1, ?2, ?3, ?4)",
+            params![
+                id,
+                conversation_id,
+                role_name(event.author),
+                record.timestamp
+            ],
+        )?,
+        // Variants are searchable as the message they replace, by the same author.
+        ConversationEvent::MessageAlternativeAdded(ConversationMessageAlternativeAddedEvent {
+            message_id,
+            ..
+        })
+        | ConversationEvent::MessageEdited(ConversationMessageEditedEvent { message_id, .. }) => {
+            connection.execute(
+                "INSERT INTO search_documents (event_id, conversation_id, role, timestamp)
+                SELECT ?1, ?2, role, ?3 FROM search_documents
+                WHERE event_id = ?4 AND role IS NOT NULL",
+                params![
+                    id,
+                    conversation_id,
+                    record.timestamp,
+                    message_id.to_string()
+                ],
+            )?
+        }
+        ConversationEvent::TitleChange(_) => {
+            connection.execute(
+                "DELETE FROM search_documents WHERE conversation_id = ?1 AND role IS NULL",
+                params![conversation_id],
+            )?;
+            connection.execute(
+                "INSERT INTO search_documents (event_id, conversation_id, role, timestamp)
+                VALUES (?1, ?2, NULL, ?3)",
+                params![id, conversation_id, record.timestamp],
+            )?
+        }
+        _ => return Ok(()),
+    };
+    if indexed == 0 {
+        return Ok(());
+    }
+    let mut statement = connection.prepare_cached(
+        "INSERT OR IGNORE INTO search_postings (token, event_id) VALUES (?1, ?2)",
+    )?;
+    for (token, _, _) in tokenize(text) {
+        statement.execute(params![token, id])?;
+    }
+    Ok(())
+}
+
+/// Name of the role as it is serialized, which is how the `role` column holds it.
+fn role_name(role: ChatRole) -> String {
+    match serde_json::to_value(role) {
+        Ok(Value::String(name)) => name,
+        _ => unreachable!("roles serialize to their name"),
+    }
+}
+
+fn read_document(row: &rusqlite::Row) -> rusqlite::Result<SearchDocument> {
+    let role = match row.get::<_, Option<String>>(2)? {
+        Some(name) => Some(serde_json::from_value(Value::String(name)).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(2, rusqlite::types::Type::Text, Box::new(e))
+        })?),
+        None => None,
+    };
+    Ok(SearchDocument {
+        event_id: parse_uuid(0, row.get(0)?)?,
+        conversation_id: parse_uuid(1, row.get(1)?)?,
+        role,
+        timestamp: row.get(3)?,
+    })
+}
+
+fn insert_change(connection: &Connection, change: &ConversationChange) -> rusqlite::Result<()> {
+    match change {
+        ConversationChange::ConversationCreated(id) => insert_conversation(connection, id)?,
+        ConversationChange::EventAdded(record) => insert_event(connection, record)?,
+        ConversationChange::ConversationRemoved(id) => {
+            connection.execute(
+                "DELETE FROM conversations WHERE id = ?1",
+                params![id.to_string()],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn insert_summary(connection: &Connection, summary: &ConversationSummary) -> rusqlite::Result<()> {
+    let text = serde_json::to_string(summary)
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    connection.execute(
+        "INSERT OR REPLACE INTO conversation_summaries (id, summary) VALUES (?1, ?2)",
+        params![summary.id.to_string(), text],
+    )?;
+    Ok(())
+}
+
+fn parse_uuid(index: usize, text: String) -> rusqlite::Result<Uuid> {
+    Uuid::parse_str(&text).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+fn parse_optional_uuid(index: usize, text: Option<String>) -> rusqlite::Result<Option<Uuid>> {
+    text.map(|text| parse_uuid(index, text)).transpose()
+}
+
+fn read_record(row: &rusqlite::Row) -> rusqlite::Result<ConversationEventRecord> {
+    let event: String = row.get(3)?;
+    let event: ConversationEvent = serde_json::from_str(&event).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Text, Box::new(e))
+    })?;
+    Ok(ConversationEventRecord {
+        id: parse_uuid(0, row.get(0)?)?,
+        conversation_id: parse_uuid(1, row.get(1)?)?,
+        timestamp: row.get(2)?,
+        event,
+    })
+}
+
+fn read_conversation(connection: &Connection, id: &Uuid) -> io::Result<Option<Conversation>> {
+    let exists: i64 = connection
+        .query_row(
+            "SELECT COUNT(*) FROM conversations WHERE id = ?1",
+            params![id.to_string()],
+            |row| row.get(0),
+        )
+        .map_err(sqlite_error)?;
+    if exists == 0 {
+        return Ok(None);
+    }
+    let mut statement = connection
+        .prepare(
+            "SELECT id, conversation_id, timestamp, event FROM events
+            WHERE conversation_id = ?1 ORDER BY rowid",
+        )
+        .map_err(sqlite_error)?;
+    let history = statement
+        .query_map(params![id.to_string()], read_record)
+        .map_err(sqlite_error)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    Ok(Some(Conversation { id: *id, history }))
+}
+
+impl ConversationStore for SqliteStore {
+    fn loads_lazily(&self) -> bool {
+        true
+    }
+    fn load(&self) -> io::Result<HashMap<Uuid, Conversation>> {
+        let connection = self.connection.lock().unwrap();
+        let mut conversations = HashMap::new();
+
+        let mut statement = connection
+            .prepare("SELECT id FROM conversations")
+            .map_err(sqlite_error)?;
+        let ids = statement
+            .query_map([], |row| parse_uuid(0, row.get(0)?))
+            .map_err(sqlite_error)?;
+        for id in ids {
+            let id = id.map_err(sqlite_error)?;
+            conversations.insert(
+                id,
+                Conversation {
+                    id,
+                    history: Vec::new(),
+                },
+            );
+        }
+
+        // Rows are inserted in the order the records were added, which ties in timestamps don't capture.
+        let mut statement = connection
+            .prepare("SELECT id, conversation_id, timestamp, event FROM events ORDER BY rowid")
+            .map_err(sqlite_error)?;
+        let records = statement.query_map([], read_record).map_err(sqlite_error)?;
+        for record in records {
+            let record = record.map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            if let Some(conv) = conversations.get_mut(&record.conversation_id) {
+                conv.history.push(record);
+            }
+        }
+        Ok(conversations)
+    }
+    /// Reads the saved summaries, rebuilding those that are missing or no longer parse.
+    fn load_index(&self) -> io::Result<HashMap<Uuid, ConversationSummary>> {
+        let connection = self.connection.lock().unwrap();
+        let rows = {
+            let mut statement = connection
+                .prepare(
+                    "SELECT conversations.id, conversation_summaries.summary FROM conversations
+                    LEFT JOIN conversation_summaries ON conversation_summaries.id = conversations.id",
+                )
+                .map_err(sqlite_error)?;
+            let rows = statement
+                .query_map([], |row| {
+                    Ok((
+                        parse_uuid(0, row.get(0)?)?,
+                        row.get::<_, Option<String>>(1)?,
+                    ))
+                })
+                .map_err(sqlite_error)?;
+            rows.collect::<Result<Vec<_>, _>>().map_err(sqlite_error)?
+        };
+
+        let mut index = HashMap::new();
+        for (id, summary) in rows {
+            let summary = match summary.and_then(|text| serde_json::from_str(&text).ok()) {
+                Some(summary) => summary,
+                None => {
+                    let Some(conv) = read_conversation(&connection, &id)? else {
+                        continue;
+                    };
+                    let summary = conv.summarize();
+                    insert_summary(&connection, &summary).map_err(sqlite_error)?;
+                    summary
+                }
+            };
+            index.insert(id, summary);
+        }
+        Ok(index)
+    }
+    fn load_conversation(&self, id: &Uuid) -> io::Result<Option<Conversation>> {
+        read_conversation(&self.connection.lock().unwrap(), id)
+    }
+    fn append(
+        &self,
+        changes: &[ConversationChange],
+        summaries: &[ConversationSummary],
+    ) -> io::Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(sqlite_error)?;
+        for change in changes {
+            insert_change(&transaction, change).map_err(sqlite_error)?;
+        }
+        for summary in summaries {
+            insert_summary(&transaction, summary).map_err(sqlite_error)?;
+        }
+        transaction.commit().map_err(sqlite_error)
+    }
+    fn load_embeddings(&self) -> io::Result<Vec<StoredEmbedding>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare(
+                "SELECT embeddings.event_id, events.conversation_id, embeddings.model, embeddings.vector
+                FROM embeddings JOIN events ON events.id = embeddings.event_id",
+            )
+            .map_err(sqlite_error)?;
+        let embeddings = statement
+            .query_map([], |row| {
+                let vector: Vec<u8> = row.get(3)?;
+                Ok(StoredEmbedding {
+                    event_id: parse_uuid(0, row.get(0)?)?,
+                    conversation_id: parse_uuid(1, row.get(1)?)?,
+                    model: row.get(2)?,
+                    vector: vector
+                        .chunks_exact(4)
+                        .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+                        .collect(),
+                })
+            })
+            .map_err(sqlite_error)?;
+        embeddings
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+    fn append_embeddings(&self, embeddings: &[StoredEmbedding]) -> io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(sqlite_error)?;
+        for embedding in embeddings {
+            let vector: Vec<u8> = embedding
+                .vector
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect();
+            // Skipped if the variant was removed while its embedding was being computed.
+            transaction
+                .execute(
+                    "INSERT OR REPLACE INTO embeddings (event_id, model, vector)
+                    SELECT ?1, ?2, ?3 WHERE EXISTS (SELECT 1 FROM events WHERE id = ?1)",
+                    params![embedding.event_id.to_string(), embedding.model, vector],
+                )
+                .map_err(sqlite_error)?;
+        }
+        transaction.commit().map_err(sqlite_error)
+    }
+    /// Deleting the events cascades to their embeddings, so those are set aside and put back.
+    fn compact(&self, conversations: &HashMap<Uuid, Conversation>) -> io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(sqlite_error)?;
+        transaction
+            .execute_batch(
+                "CREATE TEMP TABLE kept_embeddings AS SELECT * FROM embeddings;
+                DELETE FROM events;
+                DELETE FROM conversations;",
+            )
+            .map_err(sqlite_error)?;
+        for (id, conv) in conversations {
+            insert_conversation(&transaction, id).map_err(sqlite_error)?;
+            for record in &conv.history {
+                insert_event(&transaction, record).map_err(sqlite_error)?;
+            }
+            insert_summary(&transaction, &conv.summarize()).map_err(sqlite_error)?;
+        }
+        transaction
+            .execute_batch(
+                "INSERT INTO embeddings SELECT * FROM kept_embeddings
+                WHERE event_id IN (SELECT id FROM events);
+                DROP TABLE kept_embeddings;",
+            )
+            .map_err(sqlite_error)?;
+        transaction.commit().map_err(sqlite_error)
+    }
+    fn find_documents(&self, query: &[String]) -> io::Result<Vec<SearchDocument>> {
+        if query.is_empty() {
+            return Ok(Vec::new());
+        }
+        // One set of ids per token, the last one covering every token it prefixes.
+        let mut sets = Vec::new();
+        let mut values = Vec::new();
+        for (i, token) in query.iter().enumerate() {
+            if i == query.len() - 1 {
+                sets.push("SELECT event_id FROM search_postings WHERE token >= ? AND token < ?");
+                values.push(token.clone());
+                values.push(format!("{}{}", token, char::MAX));
+            } else {
+                sets.push("SELECT event_id FROM search_postings WHERE token = ?");
+                values.push(token.clone());
+            }
+        }
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare(&format!(
+                "SELECT event_id, conversation_id, role, timestamp FROM search_documents
+                WHERE event_id IN ({})",
+                sets.join(" INTERSECT ")
+            ))
+            .map_err(sqlite_error)?;
+        let documents = statement
+            .query_map(params_from_iter(values), read_document)
+            .map_err(sqlite_error)?;
+        documents
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+    fn message_documents(&self) -> io::Result<Vec<SearchDocument>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare(
+                "SELECT event_id, conversation_id, role, timestamp FROM search_documents
+                WHERE role IS NOT NULL",
+            )
+            .map_err(sqlite_error)?;
+        let documents = statement
+            .query_map([], read_document)
+            .map_err(sqlite_error)?;
+        documents
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+    fn load_documents(&self, ids: &[Uuid]) -> io::Result<Vec<(SearchDocument, String)>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare(
+                "SELECT search_documents.event_id, search_documents.conversation_id,
+                    search_documents.role, search_documents.timestamp, events.event
+                FROM search_documents JOIN events ON events.id = search_documents.event_id
+                WHERE search_documents.event_id = ?1",
+            )
+            .map_err(sqlite_error)?;
+        let mut documents = Vec::new();
+        for id in ids {
+            let mut rows = statement
+                .query(params![id.to_string()])
+                .map_err(sqlite_error)?;
+            let Some(row) = rows.next().map_err(sqlite_error)? else {
+                continue;
+            };
+            let document =
+                read_document(row).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            let event: ConversationEvent =
+                serde_json::from_str(&row.get::<_, String>(4).map_err(sqlite_error)?)?;
+            if let Some(text) = event.text() {
+                documents.push((document, text.to_string()));
+            }
+        }
+        Ok(documents)
+    }
+    fn load_usage(
+        &self,
+        conversation_id: Option<&Uuid>,
+        period: &UsagePeriod,
+    ) -> io::Result<Vec<UsageRecord>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare(
+                "SELECT event_id, conversation_id, timestamp, usage FROM usage
+                WHERE (?1 IS NULL OR conversation_id = ?1)
+                    AND (?2 IS NULL OR timestamp >= ?2)
+                    AND (?3 IS NULL OR timestamp <= ?3)
+                ORDER BY rowid",
+            )
+            .map_err(sqlite_error)?;
+        let usage = statement
+            .query_map(
+                params![conversation_id.map(Uuid::to_string), period.from, period.to],
+                |row| {
+                    let usage: String = row.get(3)?;
+                    Ok(UsageRecord {
+                        event_id: parse_optional_uuid(0, row.get(0)?)?,
+                        conversation_id: parse_optional_uuid(1, row.get(1)?)?,
+                        timestamp: row.get(2)?,
+                        usage: serde_json::from_str(&usage).map_err(|e| {
+                            rusqlite::Error::FromSqlConversionFailure(
+                                3,
+                                rusqlite::types::Type::Text,
+                                Box::new(e),
+                            )
+                        })?,
+                    })
+                },
+            )
+            .map_err(sqlite_error)?;
+        usage
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+    fn append_usage(&self, usage: &[UsageRecord]) -> io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(sqlite_error)?;
+        for record in usage {
+            insert_usage(&transaction, record).map_err(sqlite_error)?;
+        }
+        transaction.commit().map_err(sqlite_error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::{
+        ChatRole, ConversationManager, ConversationMessageAddedEvent, ConversationTitleChangedEvent,
+    };
+
+    fn temp_dir() -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Adds a titled conversation and an empty one, then removes the empty one and adds a message.
+    fn save_changes(mgr: &mut ConversationManager) -> Uuid {
+        let mut kept = Conversation::new();
+        kept.add_event(ConversationTitleChangedEvent {
+            new_title: "Kept".to_string(),
+            auto_generated: false,
+            usage: None,
+        });
+        let removed = Conversation::new();
+        let (kept_id, removed_id) = (kept.id, removed.id);
+        mgr.insert(kept);
+        mgr.insert(removed);
+        mgr.write_to_disk().unwrap();
+
+        mgr.remove(&removed_id);
+        mgr.get_mut(&kept_id)
+            .unwrap()
+            .add_event(ConversationMessageAddedEvent {
+                author: ChatRole::User,
+                content: "Hello".to_string(),
+                usage: None,
+            });
+        mgr.write_to_disk().unwrap();
+        mgr.write_to_disk().unwrap();
+        kept_id
+    }
+
+    fn assert_loaded(mgr: &ConversationManager, kept_id: Uuid) {
+        let summaries = mgr.summaries();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].title, "Kept");
+        assert_eq!(summaries[0].message_count, 1);
+        let conv = mgr.get(&kept_id).unwrap();
+        assert_eq!(conv.get_title().as_str(), "Kept");
+        assert_eq!(conv.get_messages()[0].content, "Hello");
+    }
+
+    #[test]
+    fn test_event_log_replay() {
+        let dir = temp_dir();
+        let path = dir.join("conversations.json");
+        let path = path.to_str().unwrap();
+
+        let mut mgr =
+            ConversationManager::from_disk(Box::new(JsonEventLogStore::new(path)), 32).unwrap();
+        let kept_id = save_changes(&mut mgr);
+        let log_path = persistence::event_log_path(path);
+        assert_eq!(
+            std::fs::read_to_string(&log_path).unwrap().lines().count(),
+            6
+        );
+
+        // A torn last line is what a crash while appending leaves behind
+        let mut log = std::fs::read_to_string(&log_path).unwrap();
+        log.push_str("{\"EventAdded\":{\"id\"");
+        std::fs::write(&log_path, log).unwrap();
+
+        let loaded =
+            ConversationManager::from_disk(Box::new(JsonEventLogStore::new(path)), 32).unwrap();
+        assert_loaded(&loaded, kept_id);
+        assert_eq!(std::fs::read_to_string(&log_path).unwrap(), "");
+
+        // The JSON storage can't unload conversations, so they all stay loaded past the limit
+        let loaded =
+            ConversationManager::from_disk(Box::new(JsonEventLogStore::new(path)), 0).unwrap();
+        assert_loaded(&loaded, kept_id);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_snapshot_fixtures() {
+        let conversation_id = Uuid::parse_str("5f0c2a4e-8a47-4d2c-9b1f-3e6f0d9a7c11").unwrap();
+        for fixture in [
+            include_str!("../fixtures/conversations_v0.json"),
+            include_str!("../fixtures/conversations_v1.json"),
+        ] {
+            let dir = temp_dir();
+            let path = dir.join("conversations.json");
+            let path = path.to_str().unwrap();
+            std::fs::write(path, fixture).unwrap();
+
+            let conversations = JsonEventLogStore::new(path).load().unwrap();
+            let conv = &conversations[&conversation_id];
+            assert_eq!(conv.get_title().as_str(), "Rust lifetimes");
+            assert_eq!(conv.get_messages().len(), 2);
+
+            // Older versions are saved again in the latest one
+            let saved: Value =
+                serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+            assert_eq!(saved["version"], CONVERSATIONS_VERSION);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_load_event_log_fixture() {
+        let dir = temp_dir();
+        let path = dir.join("conversations.json");
+        let path = path.to_str().unwrap();
+        std::fs::write(
+            persistence::event_log_path(path),
+            include_str!("../fixtures/event_log_v0.jsonl"),
+        )
+        .unwrap();
+
+        let conversations = JsonEventLogStore::new(path).load().unwrap();
+        let conversation_id = Uuid::parse_str("8d3f1a6b-4e2c-4f9a-b7d0-1e5c9a3f6b28").unwrap();
+        assert_eq!(
+            conversations[&conversation_id].get_messages()[0].content,
+            "Hello"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sqlite_store() {
+        let dir = temp_dir();
+        let path = dir.join("conversations.sqlite3");
+        let path = path.to_str().unwrap();
+
+        let mut mgr =
+            ConversationManager::from_disk(Box::new(SqliteStore::open(path).unwrap()), 32).unwrap();
+        let kept_id = save_changes(&mut mgr);
+        drop(mgr);
+
+        let loaded =
+            ConversationManager::from_disk(Box::new(SqliteStore::open(path).unwrap()), 32).unwrap();
+        assert_loaded(&loaded, kept_id);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sqlite_search_migration() {
+        let dir = temp_dir();
+        let path = dir.join("conversations.sqlite3");
+        let path = path.to_str().unwrap();
+        let mut mgr =
+            ConversationManager::from_disk(Box::new(SqliteStore::open(path).unwrap()), 32).unwrap();
+        save_changes(&mut mgr);
+        drop(mgr);
+
+        // Databases saved before search was moved into SQLite have no postings yet
+        Connection::open(path)
+            .unwrap()
+            .execute_batch(
+                "DROP TABLE search_postings; DROP TABLE search_documents; PRAGMA user_version = 1;",
+            )
+            .unwrap();
+        let store = SqliteStore::open(path).unwrap();
+        assert_eq!(store.find_documents(&["hel".to_string()]).unwrap().len(), 1);
+        assert_eq!(
+            store.find_documents(&["kept".to_string()]).unwrap().len(),
+            1
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_json_to_sqlite() {
+        let dir = temp_dir();
+        let json_path = dir.join("conversations.json");
+        let json_path = json_path.to_str().unwrap();
+        let mut mgr =
+            ConversationManager::from_disk(Box::new(JsonEventLogStore::new(json_path)), 32)
+                .unwrap();
+        let kept_id = save_changes(&mut mgr);
+
+        let sqlite_store =
+            SqliteStore::open(dir.join("conversations.sqlite3").to_str().unwrap()).unwrap();
+        sqlite_store
+            .migrate_from(&JsonEventLogStore::new(json_path))
+            .unwrap();
+        assert!(!Path::new(json_path).exists());
+        assert!(dir.join("conversations.json.migrated").exists());
+
+        let loaded = ConversationManager::from_disk(Box::new(sqlite_store), 32).unwrap();
+        assert_loaded(&loaded, kept_id);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sqlite_embeddings() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        let mut conv = Conversation::new();
+        let record = conv
+            .add_event(ConversationMessageAddedEvent {
+                author: ChatRole::User,
+                content: "Hello".to_string(),
+                usage: None,
+            })
+            .clone();
+        store
+            .append(
+                &[
+                    ConversationChange::ConversationCreated(conv.id),
+                    ConversationChange::EventAdded(record.clone()),
+                ],
+                &[conv.summarize()],
+            )
+            .unwrap();
+
+        let embedding = |event_id| StoredEmbedding {
+            event_id,
+            conversation_id: conv.id,
+            model: "test".to_string(),
+            vector: vec![0.5, -1.25],
+        };
+        // The second variant was never saved, so its embedding is dropped
+        store
+            .append_embeddings(&[embedding(record.id), embedding(Uuid::new_v4())])
+            .unwrap();
+        let loaded = store.load_embeddings().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].event_id, record.id);
+        assert_eq!(loaded[0].vector, vec![0.5, -1.25]);
+
+        // Each model keeps its own vector of the same variant
+        store
+            .append_embeddings(&[StoredEmbedding {
+                model: "other".to_string(),
+                ..embedding(record.id)
+            }])
+            .unwrap();
+        assert_eq!(store.load_embeddings().unwrap().len(), 2);
+
+        // Compacting rewrites the events without losing their embeddings
+        store
+            .compact(&HashMap::from([(conv.id, conv.clone())]))
+            .unwrap();
+        assert_eq!(store.load_embeddings().unwrap().len(), 2);
+    }
+}
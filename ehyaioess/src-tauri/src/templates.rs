@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use uuid::Uuid;
+
+use crate::models::MyError;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct PromptTemplate {
+    pub id: Uuid,
+    pub name: String,
+    pub body: String,
+    pub system_prompt: Option<String>,
+    /// Names of the `{{variable}}` placeholders found in the body and system prompt.
+    pub variables: Vec<String>,
+}
+
+impl PromptTemplate {
+    pub fn new(name: &str, body: &str, system_prompt: Option<&str>) -> Result<Self, MyError> {
+        let mut template = Self {
+            id: Uuid::new_v4(),
+            name: String::new(),
+            body: String::new(),
+            system_prompt: None,
+            variables: Vec::new(),
+        };
+        template.update(name, body, system_prompt)?;
+        Ok(template)
+    }
+
+    pub fn update(
+        &mut self,
+        name: &str,
+        body: &str,
+        system_prompt: Option<&str>,
+    ) -> Result<(), MyError> {
+        let name = name.trim();
+        for (field, value) in [("name", name), ("body", body)] {
+            if value.trim().is_empty() {
+                return Err(MyError::InvalidTemplateFail {
+                    field: field.to_string(),
+                });
+            }
+        }
+        self.name = name.to_string();
+        self.body = body.to_string();
+        self.system_prompt = system_prompt
+            .map(str::trim)
+            .filter(|prompt| !prompt.is_empty())
+            .map(str::to_string);
+
+        self.variables = Vec::new();
+        for text in std::iter::once(&self.body).chain(self.system_prompt.as_ref()) {
+            for variable in find_placeholders(text) {
+                if !self.variables.iter().any(|known| known == variable) {
+                    self.variables.push(variable.to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the rendered body and system prompt.
+    pub fn render(
+        &self,
+        values: &HashMap<String, String>,
+    ) -> Result<(String, Option<String>), MyError> {
+        let body = render(&self.body, values)?;
+        let system_prompt = self
+            .system_prompt
+            .as_ref()
+            .map(|prompt| render(prompt, values))
+            .transpose()?;
+        Ok((body, system_prompt))
+    }
+}
+
+/// Yields the trimmed names between `{{` and `}}`, skipping empty placeholders.
+fn find_placeholders(text: &str) -> impl Iterator<Item = &str> {
+    let mut rest = text;
+    std::iter::from_fn(move || loop {
+        let start = rest.find("{{")?;
+        let end = rest[start..].find("}}")? + start;
+        let name = rest[start + 2..end].trim();
+        rest = &rest[end + 2..];
+        if !name.is_empty() {
+            return Some(name);
+        }
+    })
+}
+
+fn render(text: &str, values: &HashMap<String, String>) -> Result<String, MyError> {
+    let mut rendered = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}").map(|end| end + start) else {
+            break;
+        };
+        let name = rest[start + 2..end].trim();
+        rendered.push_str(&rest[..start]);
+        if name.is_empty() {
+            rendered.push_str(&rest[start..end + 2]);
+        } else {
+            let value = values
+                .get(name)
+                .ok_or_else(|| MyError::TemplateVariableMissingFail {
+                    name: name.to_string(),
+                })?;
+            rendered.push_str(value);
+        }
+        rest = &rest[end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+pub struct TemplateManager {
+    pub templates: HashMap<Uuid, PromptTemplate>,
+}
+impl TemplateManager {
+    pub fn new() -> Self {
+        Self {
+            templates: HashMap::new(),
+        }
+    }
+    pub fn from_disk(path: &str) -> Result<Self, std::io::Error> {
+        let file = std::fs::File::open(path)?;
+        let templates: HashMap<Uuid, PromptTemplate> = serde_json::from_reader(file)?;
+        Ok(Self { templates })
+    }
+    pub fn write_to_disk(&self, path: &str) -> Result<(), std::io::Error> {
+        crate::persistence::write_json_atomically(path, &self.templates)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_variables() {
+        let template = PromptTemplate::new(
+            "Review",
+            "Review this {{ language }} code:\n{{code}}\n{{}}",
+            Some("You are a {{language}} expert."),
+        )
+        .unwrap();
+        assert_eq!(template.variables, vec!["language", "code"]);
+    }
+
+    #[test]
+    fn test_render() {
+        let template =
+            PromptTemplate::new("Summary", "Summarize {{ text }} in {{words}} words", None)
+                .unwrap();
+        let mut values = HashMap::new();
+        values.insert("text".to_string(), "this".to_string());
+        assert!(template.render(&values).is_err());
+        values.insert("words".to_string(), "ten".to_string());
+        let (body, system_prompt) = template.render(&values).unwrap();
+        assert_eq!(body, "Summarize this in ten words");
+        assert_eq!(system_prompt, None);
+    }
+}
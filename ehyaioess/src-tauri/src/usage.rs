@@ -0,0 +1,389 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use chrono::{Datelike, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use uuid::Uuid;
+
+use crate::{
+    context::{estimate_text_tokens, estimate_tokens},
+    models::{
+        deserialize_optional_timestamp, serialize_optional_timestamp, ChatRole,
+        ConversationEventRecord,
+    },
+    providers::{ChatMessage, ChatUsage},
+};
+
+/// What a completion cost, recorded on the event holding its result.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Type)]
+pub struct CompletionUsage {
+    /// The model requested, which is what the price table is keyed by.
+    pub model: String,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    /// Time from sending the request to receiving the end of the reply.
+    pub latency_ms: u32,
+    /// Set when the provider didn't report token counts, so they were estimated.
+    #[serde(default)]
+    pub estimated: bool,
+}
+impl CompletionUsage {
+    /// Takes the counts reported by the provider, or estimates them from the messages and reply.
+    pub fn new(
+        model: &str,
+        reported: Option<ChatUsage>,
+        messages: &[ChatMessage],
+        reply: &str,
+        latency: Duration,
+    ) -> Self {
+        let (prompt_tokens, completion_tokens) = match &reported {
+            Some(usage) => (usage.prompt_tokens, usage.completion_tokens),
+            None => (
+                messages.iter().map(estimate_tokens).sum(),
+                estimate_tokens(&ChatMessage {
+                    role: ChatRole::Assistant,
+                    content: reply.to_string(),
+                }),
+            ),
+        };
+        Self {
+            model: model.to_string(),
+            prompt_tokens,
+            completion_tokens,
+            latency_ms: latency.as_millis().min(u32::MAX as u128) as u32,
+            estimated: reported.is_none(),
+        }
+    }
+    /// Takes the prompt tokens reported by the provider, or estimates them from the texts.
+    pub fn for_embeddings(
+        model: &str,
+        reported: Option<u32>,
+        texts: &[String],
+        latency: Duration,
+    ) -> Self {
+        Self {
+            model: model.to_string(),
+            prompt_tokens: reported
+                .unwrap_or_else(|| texts.iter().map(|text| estimate_text_tokens(text)).sum()),
+            completion_tokens: 0,
+            latency_ms: latency.as_millis().min(u32::MAX as u128) as u32,
+            estimated: reported.is_none(),
+        }
+    }
+}
+
+/// Price of a model in dollars per million tokens.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+}
+impl ModelPrice {
+    pub fn cost(&self, prompt_tokens: u32, completion_tokens: u32) -> f64 {
+        (prompt_tokens as f64 * self.prompt + completion_tokens as f64 * self.completion)
+            / 1_000_000.0
+    }
+}
+
+/// Start of the UTC calendar month `timestamp` falls in.
+pub fn month_start(timestamp: i64) -> i64 {
+    Utc.timestamp_millis_opt(timestamp)
+        .single()
+        .and_then(|time| {
+            Utc.with_ymd_and_hms(time.year(), time.month(), 1, 0, 0, 0)
+                .single()
+        })
+        .map_or(0, |start| start.timestamp_millis())
+}
+
+/// Limits a usage report to the completions made in a range of timestamps, both ends included.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, Type)]
+pub struct UsagePeriod {
+    #[serde(
+        default,
+        serialize_with = "serialize_optional_timestamp",
+        deserialize_with = "deserialize_optional_timestamp"
+    )]
+    pub from: Option<i64>,
+    #[serde(
+        default,
+        serialize_with = "serialize_optional_timestamp",
+        deserialize_with = "deserialize_optional_timestamp"
+    )]
+    pub to: Option<i64>,
+}
+impl UsagePeriod {
+    pub fn contains(&self, timestamp: i64) -> bool {
+        self.from.map_or(true, |from| timestamp >= from)
+            && self.to.map_or(true, |to| timestamp <= to)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Type)]
+pub struct UsageTotals {
+    pub completions: u32,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    /// In dollars, leaving out the models missing from the price table.
+    pub cost: f64,
+    /// Set when some of the token counts were estimated.
+    pub estimated: bool,
+}
+impl UsageTotals {
+    fn add(&mut self, usage: &CompletionUsage, price: Option<&ModelPrice>) {
+        self.completions += 1;
+        self.prompt_tokens = self.prompt_tokens.saturating_add(usage.prompt_tokens);
+        self.completion_tokens = self
+            .completion_tokens
+            .saturating_add(usage.completion_tokens);
+        if let Some(price) = price {
+            self.cost += price.cost(usage.prompt_tokens, usage.completion_tokens);
+        }
+        self.estimated |= usage.estimated;
+    }
+}
+
+fn most_expensive_first(a: &UsageTotals, b: &UsageTotals) -> Ordering {
+    let tokens = |totals: &UsageTotals| {
+        u64::from(totals.prompt_tokens) + u64::from(totals.completion_tokens)
+    };
+    b.cost.total_cmp(&a.cost).then(tokens(b).cmp(&tokens(a)))
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ConversationUsage {
+    pub conversation_id: Uuid,
+    pub title: String,
+    pub totals: UsageTotals,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct ModelUsage {
+    pub model: String,
+    pub totals: UsageTotals,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct DailyUsage {
+    /// UTC date as `YYYY-MM-DD`.
+    pub day: String,
+    pub totals: UsageTotals,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Type)]
+pub struct UsageReport {
+    pub totals: UsageTotals,
+    /// Most expensive first, then most tokens first, like `by_model`. Requests made outside of
+    /// a conversation, like embeddings, only count towards the other totals.
+    pub by_conversation: Vec<ConversationUsage>,
+    pub by_model: Vec<ModelUsage>,
+    /// Oldest first, days without completions are left out.
+    pub by_day: Vec<DailyUsage>,
+    /// Models used in the period that have no price, their cost is left out of every total.
+    pub unpriced_models: Vec<String>,
+}
+
+/// The usage of one request, saved apart from the conversations so removing them doesn't lower
+/// what was spent.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct UsageRecord {
+    /// The record holding the completion, `None` for requests that don't add one.
+    pub event_id: Option<Uuid>,
+    pub conversation_id: Option<Uuid>,
+    pub timestamp: i64,
+    pub usage: CompletionUsage,
+}
+impl UsageRecord {
+    pub fn from_record(record: &ConversationEventRecord) -> Option<Self> {
+        Some(Self {
+            event_id: Some(record.id),
+            conversation_id: Some(record.conversation_id),
+            timestamp: record.timestamp,
+            usage: record.event.usage()?.clone(),
+        })
+    }
+    /// Usage of a request made outside of any conversation.
+    pub fn standalone(usage: CompletionUsage) -> Self {
+        Self {
+            event_id: None,
+            conversation_id: None,
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            usage,
+        }
+    }
+}
+
+/// Dollars spent on the requests, leaving out the models missing from the price table.
+pub fn spent(records: &[UsageRecord], prices: &HashMap<String, ModelPrice>) -> f64 {
+    records
+        .iter()
+        .filter_map(|record| {
+            let price = prices.get(&record.usage.model)?;
+            Some(price.cost(record.usage.prompt_tokens, record.usage.completion_tokens))
+        })
+        .sum()
+}
+
+/// Aggregates the usage of the requests, `titles` names the conversations.
+pub fn report(
+    records: &[UsageRecord],
+    prices: &HashMap<String, ModelPrice>,
+    titles: &HashMap<Uuid, String>,
+) -> UsageReport {
+    let mut totals = UsageTotals::default();
+    let mut by_conversation: HashMap<Uuid, UsageTotals> = HashMap::new();
+    let mut by_model: HashMap<&str, UsageTotals> = HashMap::new();
+    let mut by_day: BTreeMap<String, UsageTotals> = BTreeMap::new();
+    for record in records {
+        let price = prices.get(&record.usage.model);
+        let day = Utc
+            .timestamp_millis_opt(record.timestamp)
+            .single()
+            .map_or_else(String::new, |time| time.format("%Y-%m-%d").to_string());
+        totals.add(&record.usage, price);
+        if let Some(conversation_id) = record.conversation_id {
+            by_conversation
+                .entry(conversation_id)
+                .or_default()
+                .add(&record.usage, price);
+        }
+        by_model
+            .entry(&record.usage.model)
+            .or_default()
+            .add(&record.usage, price);
+        by_day.entry(day).or_default().add(&record.usage, price);
+    }
+
+    let mut by_conversation: Vec<ConversationUsage> = by_conversation
+        .into_iter()
+        .map(|(conversation_id, totals)| ConversationUsage {
+            conversation_id,
+            title: titles.get(&conversation_id).cloned().unwrap_or_default(),
+            totals,
+        })
+        .collect();
+    by_conversation.sort_by(|a, b| most_expensive_first(&a.totals, &b.totals));
+    let mut unpriced_models: Vec<String> = by_model
+        .keys()
+        .filter(|model| !prices.contains_key(**model))
+        .map(|model| model.to_string())
+        .collect();
+    unpriced_models.sort();
+    let mut by_model: Vec<ModelUsage> = by_model
+        .into_iter()
+        .map(|(model, totals)| ModelUsage {
+            model: model.to_string(),
+            totals,
+        })
+        .collect();
+    by_model.sort_by(|a, b| most_expensive_first(&a.totals, &b.totals));
+
+    UsageReport {
+        totals,
+        by_conversation,
+        by_model,
+        by_day: by_day
+            .into_iter()
+            .map(|(day, totals)| DailyUsage { day, totals })
+            .collect(),
+        unpriced_models,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        models::{Conversation, ConversationMessageAddedEvent},
+        storage::{ConversationChange, ConversationStore, SqliteStore},
+    };
+
+    #[test]
+    fn test_usage_report() {
+        let mut conv = Conversation::new();
+        let usage = |model: &str, prompt_tokens, completion_tokens| CompletionUsage {
+            model: model.to_string(),
+            prompt_tokens,
+            completion_tokens,
+            latency_ms: 500,
+            estimated: false,
+        };
+        for (timestamp, usage) in [
+            (0, None),
+            (1_000, Some(usage("gpt-4", 1_000, 500))),
+            (86_400_000, Some(usage("gpt-4", 2_000, 100))),
+            (86_400_001, Some(usage("llama-3", 300, 300))),
+        ] {
+            conv.add_event(ConversationMessageAddedEvent {
+                author: ChatRole::Assistant,
+                content: String::new(),
+                usage,
+            });
+            conv.history.last_mut().unwrap().timestamp = timestamp;
+        }
+        let store = SqliteStore::open(":memory:").unwrap();
+        let mut changes = vec![ConversationChange::ConversationCreated(conv.id)];
+        changes.extend(
+            conv.history
+                .iter()
+                .cloned()
+                .map(ConversationChange::EventAdded),
+        );
+        store.append(&changes, &[]).unwrap();
+        store
+            .append_usage(&[UsageRecord {
+                timestamp: 86_400_002,
+                ..UsageRecord::standalone(usage("text-embedding-3-small", 50, 0))
+            }])
+            .unwrap();
+        let prices = HashMap::from([(
+            "gpt-4".to_string(),
+            ModelPrice {
+                prompt: 30.0,
+                completion: 60.0,
+            },
+        )]);
+        let titles = HashMap::from([(conv.id, "Pricing".to_string())]);
+        let aggregate = |period: &UsagePeriod| {
+            report(&store.load_usage(None, period).unwrap(), &prices, &titles)
+        };
+
+        let all = UsagePeriod::default();
+        let usage_report = aggregate(&all);
+        assert_eq!(usage_report.totals.completions, 4);
+        assert_eq!(usage_report.totals.prompt_tokens, 3_350);
+        assert!((usage_report.totals.cost - 0.126).abs() < 1e-9);
+        assert_eq!(usage_report.by_conversation.len(), 1);
+        assert_eq!(usage_report.by_conversation[0].title, "Pricing");
+        assert_eq!(usage_report.by_conversation[0].totals.completions, 3);
+        assert_eq!(usage_report.by_model[0].model, "gpt-4");
+        assert_eq!(usage_report.by_model[1].totals.cost, 0.0);
+        assert_eq!(
+            usage_report.unpriced_models,
+            vec!["llama-3".to_string(), "text-embedding-3-small".to_string()]
+        );
+        assert_eq!(usage_report.by_day.len(), 2);
+        assert_eq!(usage_report.by_day[0].day, "1970-01-01");
+        assert_eq!(usage_report.by_day[1].totals.completions, 3);
+
+        let period = UsagePeriod {
+            from: Some(86_400_000),
+            to: None,
+        };
+        assert_eq!(aggregate(&period).totals.completions, 3);
+        let conversation_usage = store.load_usage(Some(&conv.id), &period).unwrap();
+        assert_eq!(conversation_usage.len(), 2);
+        assert!((spent(&conversation_usage, &prices) - 0.066).abs() < 1e-9);
+        assert_eq!(month_start(40 * 86_400_000 + 5), 31 * 86_400_000);
+
+        // What was spent stays in the report once the conversation is removed
+        store
+            .append(&[ConversationChange::ConversationRemoved(conv.id)], &[])
+            .unwrap();
+        assert_eq!(aggregate(&all).totals.completions, 4);
+    }
+}
@@ -14,8 +14,28 @@ export function listConversationTitles() {
     return invoke()<{ [key: string]: string }>("list_conversation_titles")
 }
 
+export function listConversationTitlesWithStatus(status: ConversationStatus) {
+    return invoke()<{ [key: string]: string }>("list_conversation_titles_with_status", { status })
+}
+
 export function getConversationMessages(conversationId: string) {
-    return invoke()<ConversationMessageAddedEvent[]>("get_conversation_messages", { conversationId })
+    return invoke()<ConversationMessage[]>("get_conversation_messages", { conversationId })
+}
+
+export function getConversationHistoryPage(conversationId: string, before: string | null, after: string | null, limit: number) {
+    return invoke()<ConversationHistoryPage>("get_conversation_history_page", { conversationId,before,after,limit })
+}
+
+export function getConversationMessagesPage(conversationId: string, before: string | null, after: string | null, limit: number) {
+    return invoke()<ConversationMessagesPage>("get_conversation_messages_page", { conversationId,before,after,limit })
+}
+
+export function searchConversations(query: string, filters: SearchFilters) {
+    return invoke()<SearchResult[]>("search_conversations", { query,filters })
+}
+
+export function semanticSearch(query: string, topK: number) {
+    return invoke()<SemanticSearchResult[]>("semantic_search", { query,topK })
 }
 
 export function getConversationTitle(conversationId: string) {
@@ -34,6 +54,38 @@ export function setConversationTitle(conversationId: string, newTitle: string) {
     return invoke()<null>("set_conversation_title", { conversationId,newTitle })
 }
 
+export function archiveConversation(conversationId: string) {
+    return invoke()<null>("archive_conversation", { conversationId })
+}
+
+export function deleteConversation(conversationId: string) {
+    return invoke()<null>("delete_conversation", { conversationId })
+}
+
+export function restoreConversation(conversationId: string) {
+    return invoke()<null>("restore_conversation", { conversationId })
+}
+
+export function purgeTrash() {
+    return invoke()<string[]>("purge_trash")
+}
+
+export function getConversationSettings(conversationId: string) {
+    return invoke()<ConversationSettings>("get_conversation_settings", { conversationId })
+}
+
+export function setConversationSettings(conversationId: string, settings: ConversationSettings) {
+    return invoke()<null>("set_conversation_settings", { conversationId,settings })
+}
+
+export function getSystemPrompt(conversationId: string) {
+    return invoke()<string | null>("get_system_prompt", { conversationId })
+}
+
+export function setSystemPrompt(conversationId: string, content: string) {
+    return invoke()<null>("set_system_prompt", { conversationId,content })
+}
+
 export function newConversationUserMessage(conversationId: string, content: string) {
     return invoke()<null>("new_conversation_user_message", { conversationId,content })
 }
@@ -42,6 +94,50 @@ export function newConversationAssistantMessage(conversationId: string) {
     return invoke()<null>("new_conversation_assistant_message", { conversationId })
 }
 
+export function regenerateAssistantMessage(conversationId: string) {
+    return invoke()<null>("regenerate_assistant_message", { conversationId })
+}
+
+export function selectMessageAlternative(conversationId: string, messageId: string, alternativeId: string) {
+    return invoke()<null>("select_message_alternative", { conversationId,messageId,alternativeId })
+}
+
+export function editConversationUserMessage(conversationId: string, messageId: string, content: string) {
+    return invoke()<null>("edit_conversation_user_message", { conversationId,messageId,content })
+}
+
+export function cancelAssistantMessage(conversationId: string) {
+    return invoke()<null>("cancel_assistant_message", { conversationId })
+}
+
+export function listModels() {
+    return invoke()<string[]>("list_models")
+}
+
+export function getUsageReport(period: UsagePeriod) {
+    return invoke()<UsageReport>("get_usage_report", { period })
+}
+
+export function listTemplates() {
+    return invoke()<PromptTemplate[]>("list_templates")
+}
+
+export function createTemplate(name: string, body: string, systemPrompt: string | null) {
+    return invoke()<PromptTemplate>("create_template", { name,body,systemPrompt })
+}
+
+export function editTemplate(templateId: string, name: string, body: string, systemPrompt: string | null) {
+    return invoke()<PromptTemplate>("edit_template", { templateId,name,body,systemPrompt })
+}
+
+export function deleteTemplate(templateId: string) {
+    return invoke()<null>("delete_template", { templateId })
+}
+
+export function instantiateTemplate(templateId: string, values: { [key: string]: string }) {
+    return invoke()<Conversation>("instantiate_template", { templateId,values })
+}
+
 export function listFiles() {
     return invoke()<string[]>("list_files")
 }
@@ -52,10 +148,49 @@ export function wrapEventPayloads(bruh: WrapType) {
 
 export type Conversation = { id: string; history: ConversationEventRecord[] }
 export type ChatRole = "System" | "Assistant" | "User"
-export type WrapType = "None" | { ConversationTitleChangedEventPayload: ConversationTitleChangedEventPayload } | { ConversationMessageAddedEventPayload: ConversationMessageAddedEventPayload }
-export type ConversationEvent = { MessageAdded: ConversationMessageAddedEvent } | { TitleChange: ConversationTitleChangedEvent }
-export type ConversationTitleChangedEventPayload = { conversation_id: string; new_title: string }
-export type ConversationMessageAddedEventPayload = { conversation_id: string; author: ChatRole; content: string }
+export type WrapType = "None" | { ConversationTitleChangedEventPayload: ConversationTitleChangedEventPayload } | { ConversationMessageAddedEventPayload: ConversationMessageAddedEventPayload } | { ConversationMessageDeltaEventPayload: ConversationMessageDeltaEventPayload } | { ConversationMessageCancelledEventPayload: ConversationMessageCancelledEventPayload } | { ConversationSettingsChangedEventPayload: ConversationSettingsChangedEventPayload } | { ConversationSystemPromptChangedEventPayload: ConversationSystemPromptChangedEventPayload } | { ConversationMessageAlternativeEventPayload: ConversationMessageAlternativeEventPayload } | { ConversationStatusChangedEventPayload: ConversationStatusChangedEventPayload } | { ConversationPurgedEventPayload: ConversationPurgedEventPayload } | { ConversationContextSummarizedEventPayload: ConversationContextSummarizedEventPayload } | { SpendingLimitWarningEventPayload: SpendingLimitWarningEventPayload } | { AssistantRetryingEventPayload: AssistantRetryingEventPayload } | { MyError: MyError }
+export type ConversationEvent = { MessageAdded: ConversationMessageAddedEvent } | { TitleChange: ConversationTitleChangedEvent } | { MessageCancelled: ConversationMessageCancelledEvent } | { SettingsChanged: ConversationSettingsChangedEvent } | { SystemPromptChanged: ConversationSystemPromptChangedEvent } | { MessageAlternativeAdded: ConversationMessageAlternativeAddedEvent } | { MessageAlternativeSelected: ConversationMessageAlternativeSelectedEvent } | { MessageEdited: ConversationMessageEditedEvent } | { StatusChanged: ConversationStatusChangedEvent } | { ContextSummarized: ConversationContextSummarizedEvent }
+export type ConversationTitleChangedEventPayload = { conversation_id: string; new_title: string; auto_generated: boolean }
+export type ConversationMessageAddedEventPayload = { conversation_id: string; message_id: string; author: ChatRole; content: string }
+export type ConversationMessageDeltaEventPayload = { conversation_id: string; message_id: string; chunk: string; sequence: number }
+export type ConversationMessageCancelledEventPayload = { conversation_id: string; message_id: string; partial_content: string }
 export type ConversationEventRecord = { id: string; conversation_id: string; timestamp: string; event: ConversationEvent }
-export type ConversationMessageAddedEvent = { author: ChatRole; content: string }
-export type ConversationTitleChangedEvent = { new_title: string }
+export type ConversationMessageAddedEvent = { author: ChatRole; content: string; usage?: CompletionUsage | null }
+export type ConversationTitleChangedEvent = { new_title: string; auto_generated: boolean; usage?: CompletionUsage | null }
+export type ConversationMessageCancelledEvent = { author: ChatRole; partial_content: string; usage?: CompletionUsage | null }
+export type ConversationSettings = { model: string | null; temperature: number | null; top_p: number | null; max_tokens: number | null; presence_penalty: number | null; frequency_penalty: number | null }
+export type ConversationSettingsChangedEvent = { settings: ConversationSettings }
+export type ConversationSettingsChangedEventPayload = { conversation_id: string; settings: ConversationSettings }
+export type ConversationSystemPromptChangedEvent = { content: string }
+export type ConversationSystemPromptChangedEventPayload = { conversation_id: string; content: string }
+export type PromptTemplate = { id: string; name: string; body: string; system_prompt: string | null; variables: string[] }
+export type ConversationMessage = { id: string; author: ChatRole; content: string; selected_alternative_id: string; alternatives: ConversationMessageAlternative[] }
+export type ConversationMessageAlternative = { id: string; content: string }
+export type ConversationMessageAlternativeAddedEvent = { message_id: string; content: string; cancelled: boolean; usage?: CompletionUsage | null }
+export type ConversationMessageAlternativeSelectedEvent = { message_id: string; alternative_id: string }
+export type ConversationMessageAlternativeEventPayload = { conversation_id: string; message_id: string; alternative_id: string; content: string }
+export type ConversationMessageEditedEvent = { message_id: string; content: string }
+export type ConversationStatus = "Active" | "Archived" | "Trashed"
+export type ConversationStatusChangedEvent = { status: ConversationStatus }
+export type ConversationStatusChangedEventPayload = { conversation_id: string; status: ConversationStatus; title: string }
+export type ConversationHistoryPage = { records: ConversationEventRecord[]; has_more_before: boolean; has_more_after: boolean }
+export type ConversationMessagesPage = { messages: ConversationMessage[]; has_more_before: boolean; has_more_after: boolean }
+export type SearchFilters = { author: ChatRole | null; from: string | null; to: string | null; status: ConversationStatus | null }
+export type SearchResult = { conversation_id: string; conversation_title: string; event_id: string; role: ChatRole | null; timestamp: string; snippet: string; highlights: HighlightRange[] }
+export type HighlightRange = { start: number; end: number }
+export type SemanticSearchResult = { result: SearchResult; score: number }
+export type ConversationPurgedEventPayload = { conversation_id: string }
+export type ConversationContextSummarizedEvent = { summary: string; through_alternative_id: string; usage?: CompletionUsage | null }
+export type CompletionUsage = { model: string; prompt_tokens: number; completion_tokens: number; latency_ms: number; estimated: boolean }
+export type SpendingLimit = "Monthly" | "Conversation"
+export type SpendingLimitWarningEventPayload = { conversation_id: string | null; limit: SpendingLimit; spent: number; cap: number }
+export type AssistantRetryingEventPayload = { conversation_id: string; message_id: string; attempt: number; max_attempts: number; delay_ms: number; reason: string }
+export type MyError = { UUIDParseFail: { value: string } } | { FindByIDFail: { id: string } } | { EmitFail: { event: string; message: string } } | { ConversationWriteToDiskFail: { conversation_id: string | null; message: string } } | "NoConfigDirFail" | { UserNotLatestAuthorInConversationFail: { conversation_id: string } } | { ConversationEmptyFail: { conversation_id: string } } | { ConversationAIResponseFail: ProviderError } | { GenerationInProgressFail: { conversation_id: string } } | { NoGenerationInProgressFail: { conversation_id: string } } | { ListModelsFail: ProviderError } | { InvalidSettingsFail: { setting: string } } | { InvalidTemplateFail: { field: string } } | { TemplateVariableMissingFail: { name: string } } | { TemplateWriteToDiskFail: { message: string } } | { NothingToRegenerateFail: { conversation_id: string } } | { NotAUserMessageFail: { message_id: string } } | { EmptyMessageFail: { message_id: string } } | { ConversationReadFromDiskFail: { conversation_id: string; message: string } } | { UsageReadFromDiskFail: { message: string } } | { PageCursorNotFoundFail: { cursor: string } } | { SearchFail: { message: string } } | { EmbeddingFail: ProviderError } | { ContextWindowExceededFail: { tokens: number; available: number } } | { SpendingLimitExceededFail: { limit: SpendingLimit; spent: number; cap: number } } | { UnpricedModelFail: { model: string } } | { DirListFail: { path: string; message: string } }
+export type ProviderError = { status: number | null; message: string; retryable: boolean }
+export type UsagePeriod = { from: string | null; to: string | null }
+export type UsageTotals = { completions: number; prompt_tokens: number; completion_tokens: number; cost: number; estimated: boolean }
+export type ConversationUsage = { conversation_id: string; title: string; totals: UsageTotals }
+export type ModelUsage = { model: string; totals: UsageTotals }
+export type DailyUsage = { day: string; totals: UsageTotals }
+export type UsageReport = { totals: UsageTotals; by_conversation: ConversationUsage[]; by_model: ModelUsage[]; by_day: DailyUsage[]; unpriced_models: string[] }
+export type ConversationContextSummarizedEventPayload = { conversation_id: string; summary: string; through_alternative_id: string }